@@ -6,7 +6,7 @@ use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::thread;
 
-const FILENAME: &'static str = "/bin/ls";
+const FILENAME: &str = "/bin/ls";
 
 pub struct R2PipeAsync {
     tx: Sender<String>,
@@ -21,10 +21,10 @@ impl R2PipeAsync {
         let (tx, rx) = channel(); // query
         let (tx2, rx2) = channel(); // result
         R2PipeAsync {
-            tx: tx,
-            rx: rx,
-            tx2: tx2,
-            rx2: rx2,
+            tx,
+            rx,
+            tx2,
+            rx2,
             cbs: Vec::new(),
         }
     }
@@ -44,7 +44,7 @@ impl R2PipeAsync {
         let child = thread::spawn(move || {
             let mut r2p = match R2Pipe::in_session() {
                 Some(_) => R2Pipe::open(),
-                None => R2Pipe::spawn(FILENAME.to_owned(), None),
+                None => R2Pipe::spawn(FILENAME, None),
             }
             .unwrap();
             loop {
@@ -62,15 +62,9 @@ impl R2PipeAsync {
         });
 
         // main loop
-        loop {
-            let msg = self.rx2.recv();
-            if msg.is_ok() {
-                let res = msg.unwrap();
-                if let Some(cb) = self.cbs.pop() {
-                    cb(res.trim().to_string());
-                } else {
-                    break;
-                }
+        while let Ok(res) = self.rx2.recv() {
+            if let Some(cb) = self.cbs.pop() {
+                cb(res.trim().to_string());
             } else {
                 break;
             }
@@ -6,7 +6,7 @@ fn main() -> Result<()> {
         use r2pipe::R2Pipe;
         use serde_json;
 
-        let mut r2p = R2Pipe::http("http://localhost:9080")?;
+        let mut r2p = R2Pipe::http("http://localhost:9080");
 
         let json = r2p.cmdj("ij")?;
         println!("{}", serde_json::to_string_pretty(&json)?);
@@ -1,10 +1,8 @@
-use serde_json;
-
 use r2pipe::R2PipeSpawnOptions;
 use r2pipe::{R2Pipe, Result};
 
 fn test_trim() -> Result<()> {
-    let mut ns = R2Pipe::spawn("/bin/ls".to_owned(), None)?;
+    let mut ns = R2Pipe::spawn("/bin/ls", None)?;
     println!("(({}))", ns.cmd("\n\n?e hello world\n\n")?);
     println!("(({}))", ns.cmd("\n\n?e hello world\n\n")?);
     println!("(({}))", ns.cmd("\n\n?e hello world\n\n")?);
@@ -21,7 +19,7 @@ fn main() -> Result<()> {
     };
     let mut r2p = match R2Pipe::in_session() {
         Some(_) => R2Pipe::open()?,
-        None => R2Pipe::spawn("/bin/ls".to_owned(), Some(opts))?,
+        None => R2Pipe::spawn("/bin/ls", Some(opts))?,
     };
 
     println!("{}", r2p.cmd("?e Hello World")?);
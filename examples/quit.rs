@@ -1,9 +1,9 @@
 use r2pipe::{R2Pipe, Result};
 
 fn main() -> Result<()> {
-    let mut r2p = R2Pipe::spawn("/bin/ls".to_owned(), None)?;
+    let mut r2p = R2Pipe::spawn("/bin/ls", None)?;
     println!("{}", r2p.cmd("?e Hello")?);
-    if let Err(_) = r2p.cmd("q") {
+    if r2p.cmd("q").is_err() {
         // !killall r2") {
         println!("Quit happens!");
     } else {
@@ -1,29 +1,21 @@
-use r2pipe::{R2Pipe, Result};
+use r2pipe::r2pipe::R2Threads;
+use r2pipe::Result;
 
 fn main() -> Result<()> {
     // Lets spawn some r2pipes to open some binaries
-    // First two arguments for R2Pipe::threads() are the same as for R2Pipe::spawn() but inside vectors
-    // Third and last argument is an option of a callback function
-    let pipes = match R2Pipe::threads(
-        vec!["/bin/ls", "/bin/id", "/bin/cat"],
-        vec![None, None, None],
-        None,
-    ) {
-        Ok(p) => p,
-        Err(e) => {
-            println!("Error spawning Pipes: {}", e);
-            return Ok(());
-        }
-    };
-
-    // At this point we can iter through all of our r2pipes and send some commands
-    for p in pipes.iter() {
-        if let Ok(_) = p.send("ij".to_string()) {};
+    // The argument of R2Threads::new() is an option of a callback function
+    // add_pipe() takes the same arguments as R2Pipe::spawn()
+    let mut pipes = R2Threads::new(None);
+    for path in &["/bin/ls", "/bin/id", "/bin/cat"] {
+        pipes.add_pipe(*path, None);
     }
 
+    // At this point we can send some commands to all of our r2pipes
+    pipes.broadcast("ij")?;
+
     // do_other_stuff_here();
 
-    // Lets iter again and see what the pipes got
+    // Lets iter through the pipes and see what they got
     for p in pipes.iter() {
         // this will block, do "p.recv(false)" for non-blocking receive inside a loop
         if let Ok(msg) = p.recv(true) {
@@ -31,11 +23,14 @@ fn main() -> Result<()> {
         }
     }
 
-    // Finally properly close all pipes
-    // Note: For "join()" we need to borrow so pipes.iter() won't work for this
-    for p in pipes {
-        if let Ok(_) = p.send("q".to_string()) {};
-        p.handle.join().unwrap()?;
+    // Pipes can also be closed one by one
+    if let Some(res) = pipes.remove(0) {
+        res?;
+    }
+
+    // Finally properly close the remaining pipes, all at once
+    for res in pipes.join_all() {
+        res?;
     }
 
     Ok(())
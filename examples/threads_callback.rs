@@ -25,17 +25,16 @@ fn main() -> Result<()> {
 
     // At this point we can iter through all of our r2pipes and send some commands
     for p in pipes.iter() {
-        if let Ok(_) = p.send("ij".to_string()) {};
+        if p.send("ij".to_string()).is_ok() {};
     }
 
     // Meanwhile: Expecting callbacks
     std::thread::sleep(std::time::Duration::from_millis(1000));
 
     // Finally properly close all pipes
-    // Note: For "join()" we need to borrow so pipes.iter() won't work for this
+    // Note: "shutdown()" takes the pipe so pipes.iter() won't work for this
     for p in pipes {
-        if let Ok(_) = p.send("q".to_string()) {};
-        p.handle.join().unwrap()?;
+        p.shutdown()?;
     }
 
     Ok(())
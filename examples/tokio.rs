@@ -0,0 +1,20 @@
+use r2pipe::{AsyncR2Pipe, Result};
+
+const FILES: &[&str] = &["/bin/ls", "/bin/id", "/bin/cat"];
+
+// Every session runs concurrently on the same runtime, no thread per pipe
+async fn arch(file: &str) -> Result<String> {
+    let mut r2p = AsyncR2Pipe::spawn(file, None).await?;
+    let json = r2p.cmdj("ij").await?;
+    r2p.close().await;
+    Ok(json["bin"]["arch"].to_string())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let tasks: Vec<_> = FILES.iter().map(|file| tokio::spawn(arch(file))).collect();
+    for (file, task) in FILES.iter().zip(tasks) {
+        println!("{}: {}", file, task.await.unwrap()?);
+    }
+    Ok(())
+}
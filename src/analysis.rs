@@ -0,0 +1,731 @@
+//! Helpers to query and manage the state of the analysis done by r2.
+
+use crate::r2pipe::check_plain;
+use crate::{Error, R2Pipe, Result};
+
+#[cfg(feature = "json")]
+use serde_derive::Deserialize;
+
+#[cfg(feature = "json")]
+use serde_json::Value;
+
+use std::io;
+use std::path::Path;
+#[cfg(feature = "json")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// Summary of how thorough the analysis of the loaded binary was.
+///
+/// The counters are read from `aaij`, the JSON flavour of `aai`:
+///
+/// | field       | `aaij` key |
+/// |-------------|------------|
+/// | `functions` | `fcns`     |
+/// | `xrefs`     | `xrefs`    |
+/// | `calls`     | `calls`    |
+/// | `strings`   | `strings`  |
+/// | `symbols`   | `symbols`  |
+/// | `imports`   | `imports`  |
+/// | `coverage`  | `covrage`  |
+/// | `code_size` | `codesz`   |
+///
+/// r2 does not keep track of how long the analysis took, so `elapsed` is
+/// only set when the analysis is run through `R2Pipe::analyze_with_stats`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct AnalysisStats {
+    #[serde(rename = "fcns", default)]
+    pub functions: u64,
+    #[serde(default)]
+    pub xrefs: u64,
+    #[serde(default)]
+    pub calls: u64,
+    #[serde(default)]
+    pub strings: u64,
+    #[serde(default)]
+    pub symbols: u64,
+    #[serde(default)]
+    pub imports: u64,
+    /// Number of bytes covered by the analyzed functions.
+    #[serde(rename = "covrage", default)]
+    pub coverage: u64,
+    /// Size in bytes of the executable sections.
+    #[serde(rename = "codesz", default)]
+    pub code_size: u64,
+    #[serde(skip)]
+    pub elapsed: Option<Duration>,
+}
+
+#[cfg(feature = "json")]
+impl AnalysisStats {
+    /// Percentage of the code covered by the analyzed functions.
+    pub fn coverage_percent(&self) -> f64 {
+        if self.code_size == 0 {
+            return 0.0;
+        }
+        self.coverage as f64 * 100.0 / self.code_size as f64
+    }
+}
+
+/// Commands issued by `R2Pipe::reset_analysis`, in order.
+///
+/// * `af-*` removes every analyzed function.
+/// * `ax-*` removes every code and data reference.
+///
+/// The flags of the `functions` flagspace are removed afterwards: `f`
+/// lists them once `fs functions` is selected, each is removed with
+/// `f-name` and `fs *` selects all flagspaces again. `f-*` can not be used,
+/// it ignores the selected flagspace.
+///
+/// Flags created when loading the binary (symbols, imports, sections,
+/// strings...) live in other flagspaces and are left untouched.
+pub const RESET_ANALYSIS_CMDS: &[&str] = &["af-*", "ax-*"];
+
+/// Kind of analysis state which can be exported as r2 commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportKind {
+    /// Flags, exported with `f*`.
+    Flags,
+    /// Functions, exported with `afl*`.
+    Functions,
+    /// Comments, exported with `CC*`.
+    Comments,
+    /// Types, exported with `t*`.
+    Types,
+}
+
+impl ExportKind {
+    /// Returns the r2 command producing the script for this kind.
+    pub fn command(self) -> &'static str {
+        match self {
+            ExportKind::Flags => "f*",
+            ExportKind::Functions => "afl*",
+            ExportKind::Comments => "CC*",
+            ExportKind::Types => "t*",
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// A zignature, as listed by `zj`.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Zignature {
+    pub name: String,
+    #[serde(default)]
+    pub bytes: Option<String>,
+    #[serde(default)]
+    pub mask: Option<String>,
+    #[serde(default)]
+    pub addr: Option<u64>,
+}
+
+#[cfg(feature = "json")]
+fn count_sign_flags(fs: &Value) -> u64 {
+    fs.as_array()
+        .and_then(|spaces| spaces.iter().find(|s| s["name"] == "sign"))
+        .and_then(|s| s["count"].as_u64())
+        .unwrap_or(0)
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// Role of a function variable.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VariableKind {
+    /// Argument passed on the stack.
+    Arg,
+    /// Local variable.
+    Var,
+    /// Argument passed in a register.
+    Reg,
+    #[serde(other)]
+    Unknown,
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// Where the value of a function variable lives.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum VariableStorage {
+    /// At `offset` from the `base` register (base or stack pointer).
+    Stack { base: String, offset: i64 },
+    /// In the named register.
+    Register(String),
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// A variable or argument of a function, as listed by `afvj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub kind: VariableKind,
+    #[serde(rename = "type", default)]
+    pub type_: String,
+    #[serde(rename = "ref")]
+    pub storage: VariableStorage,
+}
+
+/// `afvj` groups the variables by base pointer, stack pointer and register.
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+struct FunctionVariables {
+    #[serde(default)]
+    reg: Vec<Variable>,
+    #[serde(default)]
+    sp: Vec<Variable>,
+    #[serde(default)]
+    bp: Vec<Variable>,
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// A case of a switch, jumping to `jump` when the value is `value`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JumpCase {
+    /// Address of the table entry.
+    pub addr: u64,
+    pub jump: u64,
+    pub value: u64,
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// A switch/jump table resolved by r2, from the `switch_op` of `afbj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JumpTable {
+    /// Address of the indirect jump using the table.
+    pub addr: u64,
+    pub min_val: u64,
+    pub max_val: u64,
+    /// Target of the default case, when r2 found one.
+    #[serde(default)]
+    pub def_val: Option<u64>,
+    #[serde(default)]
+    pub cases: Vec<JumpCase>,
+    /// Address of the table, the lowest entry of its cases.
+    #[serde(skip)]
+    pub base: Option<u64>,
+    /// Size of an entry of the table, taken from the data r2 defines over
+    /// it (`Cd`), or from the spacing of the cases when there is none.
+    #[serde(skip)]
+    pub elem_size: Option<u64>,
+}
+
+#[cfg(feature = "json")]
+impl JumpTable {
+    /// Smallest distance between the entries of two cases.
+    fn stride(&self) -> Option<u64> {
+        let mut entries: Vec<u64> = self.cases.iter().map(|c| c.addr).collect();
+        entries.sort_unstable();
+        entries
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .filter(|&d| d > 0)
+            .min()
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+struct BasicBlock {
+    #[serde(default)]
+    switch_op: Option<JumpTable>,
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// A method of a class.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Method {
+    pub name: String,
+    #[serde(default)]
+    pub addr: u64,
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// A C++, ObjC or Swift class found in the binary, as listed by `icj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Class {
+    #[serde(rename = "classname")]
+    pub name: String,
+    #[serde(default)]
+    pub addr: u64,
+    #[serde(default)]
+    pub methods: Vec<Method>,
+    /// Base classes. Older r2 builds report a single name, newer a list.
+    #[serde(rename = "super", default, deserialize_with = "de_super")]
+    pub bases: Vec<String>,
+}
+
+#[cfg(feature = "json")]
+fn de_super<'de, D>(d: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Super {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match serde::Deserialize::deserialize(d)? {
+        Super::One(s) if s.is_empty() => vec![],
+        Super::One(s) => vec![s],
+        Super::Many(v) => v,
+    })
+}
+
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+struct Flag {
+    name: String,
+    offset: u64,
+}
+
+impl R2Pipe {
+    /// Drops the analysis data leaving the session as it was right after
+    /// loading the binary. See `RESET_ANALYSIS_CMDS` for what gets cleared.
+    pub fn reset_analysis(&mut self) -> Result<()> {
+        for cmd in RESET_ANALYSIS_CMDS {
+            self.cmd(cmd)?;
+        }
+        self.cmd("fs functions")?;
+        let listed = self.cmd("f");
+        self.cmd("fs *")?;
+        // each line is `addr size name`
+        let deletes: Vec<String> = listed?
+            .lines()
+            .filter_map(|l| l.split_whitespace().nth(2))
+            .map(|name| format!("f-{}", name))
+            .collect();
+        let deletes: Vec<&str> = deletes.iter().map(String::as_str).collect();
+        self.cmd_batch(&deletes)?;
+        Ok(())
+    }
+
+    /// Returns the r2 script which recreates the state described by `what`.
+    ///
+    /// The output can be replayed later on with `. script.r2`.
+    pub fn export_commands(&mut self, what: ExportKind) -> Result<String> {
+        self.cmd(what.command())
+    }
+
+    /// Returns the decompiled code of the function at `addr`.
+    ///
+    /// `pdg` (r2ghidra) is tried first, falling back to the builtin `pdc`,
+    /// also when it fails in strict mode, eg. because r2ghidra is missing.
+    /// `Error::NoDecompiler` is returned when neither produced any output.
+    pub fn decompile(&mut self, addr: u64) -> Result<String> {
+        for cmd in &["pdg", "pdc"] {
+            let res = match self.cmd(&format!("{} @ {:#x}", cmd, addr)) {
+                Err(Error::CommandFailed { .. }) => continue,
+                res => res?,
+            };
+            if !res.trim().is_empty() {
+                return Ok(res);
+            }
+        }
+        Err(Error::NoDecompiler)
+    }
+
+    /// Returns the global call graph in Graphviz DOT format (`agCd`).
+    pub fn call_graph_dot(&mut self) -> Result<String> {
+        self.cmd("agCd")
+    }
+
+    /// Returns the control flow graph of the function at `addr` in Graphviz
+    /// DOT format (`agfd`).
+    pub fn cfg_dot(&mut self, addr: u64) -> Result<String> {
+        self.cmd(&format!("agfd @ {:#x}", addr))
+    }
+
+    /// Loads the signatures stored at `path`.
+    ///
+    /// FLIRT files (`.sig` and `.pat`) are loaded with `zfs`, anything else
+    /// is expected to be a zignature database and is loaded with `zo`.
+    /// Fails with `Error::UnsafeCommand` when the path contains whitespace
+    /// or characters r2 would interpret, such as `;` or `|`.
+    pub fn load_signatures<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, path.display().to_string()).into());
+        }
+        let name = path.to_str().ok_or(Error::ArgumentMismatch)?;
+        check_plain(name, true)?;
+        let cmd = match path.extension().and_then(|e| e.to_str()) {
+            Some("sig") | Some("pat") => "zfs",
+            _ => "zo",
+        };
+        self.cmd(&format!("{} {}", cmd, name))?;
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Searches the loaded signatures in the binary (`z/`) and returns the
+    /// number of matches, counted as the flags added to the `sign` flagspace.
+    pub fn apply_signatures(&mut self) -> Result<usize> {
+        let before = count_sign_flags(&self.cmdj("fsj")?);
+        self.cmd("z/")?;
+        let after = count_sign_flags(&self.cmdj("fsj")?);
+        Ok(after.saturating_sub(before) as usize)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the loaded signatures (`zj`).
+    pub fn signatures(&mut self) -> Result<Vec<Zignature>> {
+        let json = self.cmdj("zj")?;
+        Ok(serde_json::from_value(json)?)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the arguments and local variables of the function at `addr`
+    /// (`afvj`), register based ones first.
+    pub fn function_variables(&mut self, addr: u64) -> Result<Vec<Variable>> {
+        let json = self.cmdj(&format!("afvj @ {:#x}", addr))?;
+        let vars: FunctionVariables = serde_json::from_value(json)?;
+        Ok(vars.reg.into_iter().chain(vars.sp).chain(vars.bp).collect())
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the jump tables used by the function at `addr`, read from the
+    /// basic blocks listed by `afbj`. Functions without switches yield an
+    /// empty vector.
+    ///
+    /// The size of the entries is read from the metadata r2 defines over
+    /// the table (`Cj. @ base`) when resolving it.
+    pub fn jump_tables(&mut self, addr: u64) -> Result<Vec<JumpTable>> {
+        let json = self.cmdj(&format!("afbj @ {:#x}", addr))?;
+        let blocks: Vec<BasicBlock> = serde_json::from_value(json)?;
+        let mut tables: Vec<JumpTable> = blocks.into_iter().filter_map(|b| b.switch_op).collect();
+        for table in &mut tables {
+            table.base = table.cases.iter().map(|c| c.addr).min();
+            if let Some(base) = table.base {
+                let data = self
+                    .metadata_at(base)?
+                    .into_iter()
+                    .find(|m| m.kind == "Cd" && m.addr == base && m.size > 0);
+                table.elem_size = data.map(|m| m.size).or_else(|| table.stride());
+            }
+        }
+        Ok(tables)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the classes found in the binary (`icj`). Binaries without
+    /// class information yield an empty vector.
+    pub fn classes(&mut self) -> Result<Vec<Class>> {
+        let json = match self.cmdj("icj") {
+            Err(Error::EmptyResponse) => return Ok(vec![]),
+            res => res?,
+        };
+        Ok(serde_json::from_value(json)?)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the name of the closest flag at or before each of `addrs`,
+    /// formatted like `fd` does (`sym.main` or `sym.main + 16`).
+    ///
+    /// The flag list is fetched once with `fj` and looked up locally, so
+    /// this costs a single round trip whatever the number of addresses.
+    pub fn resolve_names(&mut self, addrs: &[u64]) -> Result<Vec<Option<String>>> {
+        let json = self.cmdj("fj")?;
+        let mut flags: Vec<Flag> = serde_json::from_value(json)?;
+        flags.sort_by_key(|f| f.offset);
+
+        Ok(addrs
+            .iter()
+            .map(|&addr| {
+                let n = flags.partition_point(|f| f.offset <= addr);
+                let flag = flags[..n].last()?;
+                let delta = addr - flag.offset;
+                Some(if delta == 0 {
+                    flag.name.clone()
+                } else {
+                    format!("{} + {}", flag.name, delta)
+                })
+            })
+            .collect())
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the analysis statistics of the current session (`aaij`).
+    pub fn analysis_stats(&mut self) -> Result<AnalysisStats> {
+        let json = self.cmdj("aaij")?;
+        Ok(serde_json::from_value(json)?)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Runs the analysis command `cmd` (eg. `aaa`) and returns the resulting
+    /// statistics along with the time the command took.
+    pub fn analyze_with_stats(&mut self, cmd: &str) -> Result<AnalysisStats> {
+        let start = Instant::now();
+        self.cmd(cmd)?;
+        let elapsed = start.elapsed();
+        let mut stats = self.analysis_stats()?;
+        stats.elapsed = Some(elapsed);
+        Ok(stats)
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::r2pipe::BATCH_MARKER;
+    use crate::test_util::mock;
+
+    #[test]
+    fn function_variables_parses_every_storage() {
+        let afvj = r#"{"reg":[{"name":"argc","kind":"reg","type":"int","ref":"rdi"}],
+            "sp":[{"name":"var_8h","kind":"var","type":"int64_t","ref":{"base":"rsp","offset":8}}],
+            "bp":[{"name":"arg_10h","kind":"arg","ref":{"base":"rbp","offset":-16}}]}"#;
+        let (mut r2p, sent) = mock(&[("afvj @ 0x1000", afvj)]);
+        let vars = r2p.function_variables(0x1000).unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["afvj @ 0x1000"]);
+        assert_eq!(
+            vars,
+            [
+                Variable {
+                    name: "argc".to_owned(),
+                    kind: VariableKind::Reg,
+                    type_: "int".to_owned(),
+                    storage: VariableStorage::Register("rdi".to_owned()),
+                },
+                Variable {
+                    name: "var_8h".to_owned(),
+                    kind: VariableKind::Var,
+                    type_: "int64_t".to_owned(),
+                    storage: VariableStorage::Stack {
+                        base: "rsp".to_owned(),
+                        offset: 8
+                    },
+                },
+                Variable {
+                    name: "arg_10h".to_owned(),
+                    kind: VariableKind::Arg,
+                    type_: String::new(),
+                    storage: VariableStorage::Stack {
+                        base: "rbp".to_owned(),
+                        offset: -16
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn function_variables_of_function_without_any() {
+        let (mut r2p, _) = mock(&[("afvj @ 0x1000", r#"{"reg":[],"sp":[]}"#)]);
+        assert!(r2p.function_variables(0x1000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_variable_kind() {
+        let var: Variable =
+            serde_json::from_str(r#"{"name":"x","kind":"spv","ref":"r0"}"#).unwrap();
+        assert_eq!(var.kind, VariableKind::Unknown);
+    }
+
+    const AFBJ: &str = r#"[{"addr":4096},{"addr":4112,"switch_op":{"addr":4120,
+        "min_val":0,"max_val":2,"def_val":4200,"cases":[
+        {"addr":8200,"jump":4160,"value":1},{"addr":8192,"jump":4150,"value":0},
+        {"addr":8208,"jump":4170,"value":2}]}}]"#;
+
+    #[test]
+    fn jump_tables_read_entry_size_from_metadata() {
+        let cj = r#"[{"offset":8192,"type":"CCu","name":"case.0x1018.0"},
+            {"offset":8192,"type":"Cd","size":4}]"#;
+        let (mut r2p, sent) = mock(&[("afbj @ 0x1000", AFBJ), ("Cj. @ 0x2000", cj)]);
+        let tables = r2p.jump_tables(0x1000).unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["afbj @ 0x1000", "Cj. @ 0x2000"]);
+        assert_eq!(tables.len(), 1);
+        let table = &tables[0];
+        assert_eq!((table.addr, table.def_val), (0x1018, Some(4200)));
+        assert_eq!(table.cases.len(), 3);
+        assert_eq!((table.base, table.elem_size), (Some(0x2000), Some(4)));
+    }
+
+    #[test]
+    fn jump_tables_without_metadata() {
+        let (mut r2p, _) = mock(&[("afbj @ 0x1000", AFBJ), ("Cj. @ 0x2000", "[]")]);
+        let table = &r2p.jump_tables(0x1000).unwrap()[0];
+        assert_eq!((table.base, table.elem_size), (Some(0x2000), Some(8)));
+    }
+
+    #[test]
+    fn function_without_switch() {
+        let (mut r2p, sent) = mock(&[("afbj @ 0x1000", r#"[{"addr":4096}]"#)]);
+        assert!(r2p.jump_tables(0x1000).unwrap().is_empty());
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn classes_with_methods_and_bases() {
+        let icj = r#"[{"classname":"Dog","addr":8192,"index":0,"super":["Animal","Pet"],
+            "methods":[{"name":"Dog::bark","addr":4096,"flags":[]}],"fields":[]},
+            {"classname":"Animal","addr":8256,"super":"","methods":[]},
+            {"classname":"Cat","super":"Animal"}]"#;
+        let (mut r2p, _) = mock(&[("icj", icj)]);
+        let classes = r2p.classes().unwrap();
+        assert_eq!(
+            classes[0],
+            Class {
+                name: "Dog".to_owned(),
+                addr: 0x2000,
+                methods: vec![Method {
+                    name: "Dog::bark".to_owned(),
+                    addr: 0x1000
+                }],
+                bases: vec!["Animal".to_owned(), "Pet".to_owned()],
+            }
+        );
+        assert!(classes[1].bases.is_empty());
+        assert_eq!(classes[2].bases, ["Animal"]);
+        assert!(classes[2].methods.is_empty());
+    }
+
+    #[test]
+    fn classes_of_binary_without_any() {
+        let (mut r2p, _) = mock(&[("icj", "[]")]);
+        assert!(r2p.classes().unwrap().is_empty());
+        let (mut r2p, _) = mock(&[]);
+        assert!(r2p.classes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reset_analysis_deletes_the_function_flags_in_one_line() {
+        let batch = format!("f-fcn.00001000;?e {};f-main", BATCH_MARKER);
+        let (mut r2p, sent) = mock(&[
+            ("f", "0x00001000 38 fcn.00001000\n0x00002000 10 main\n"),
+            (&batch, &format!("{}\n", BATCH_MARKER)),
+        ]);
+        r2p.reset_analysis().unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["af-*", "ax-*", "fs functions", "f", "fs *", batch.as_str()]
+        );
+    }
+
+    #[test]
+    fn analysis_stats_from_aaij() {
+        let aaij = r#"{"fcns":12,"xrefs":40,"calls":9,"strings":7,"symbols":30,
+            "imports":5,"covrage":768,"codesz":1024,"percent":75}"#;
+        let (mut r2p, sent) = mock(&[("aaij", aaij)]);
+        let stats = r2p.analyze_with_stats("aaa").unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["aaa", "aaij"]);
+        assert!(stats.elapsed.is_some());
+        assert_eq!(
+            stats,
+            AnalysisStats {
+                functions: 12,
+                xrefs: 40,
+                calls: 9,
+                strings: 7,
+                symbols: 30,
+                imports: 5,
+                coverage: 768,
+                code_size: 1024,
+                elapsed: stats.elapsed,
+            }
+        );
+        assert_eq!(stats.coverage_percent(), 75.0);
+    }
+
+    #[test]
+    fn analysis_stats_with_missing_keys() {
+        let (mut r2p, _) = mock(&[("aaij", r#"{"fcns":3}"#)]);
+        let stats = r2p.analysis_stats().unwrap();
+        assert_eq!(stats.functions, 3);
+        assert_eq!(stats.elapsed, None);
+        assert_eq!(stats.coverage_percent(), 0.0);
+    }
+
+    #[test]
+    fn load_signatures_picks_the_command_from_the_extension() {
+        let dir = std::env::temp_dir().join(format!("r2pipe-sigs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut r2p, sent) = mock(&[]);
+        for name in &["libc.sig", "libc.pat", "libc.sdb"] {
+            let path = dir.join(name);
+            std::fs::write(&path, "").unwrap();
+            r2p.load_signatures(&path).unwrap();
+        }
+        let sigs = dir.display();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                format!("zfs {}/libc.sig", sigs),
+                format!("zfs {}/libc.pat", sigs),
+                format!("zo {}/libc.sdb", sigs),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        match r2p.load_signatures(dir.join("libc.sig")) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(sent.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn apply_signatures_counts_the_new_sign_flags() {
+        let (mut r2p, sent) = mock(&[
+            (
+                "fsj",
+                r#"[{"name":"symbols","count":30},{"name":"sign","count":2}]"#,
+            ),
+            (
+                "fsj",
+                r#"[{"name":"symbols","count":30},{"name":"sign","count":7}]"#,
+            ),
+        ]);
+        assert_eq!(r2p.apply_signatures().unwrap(), 5);
+        assert_eq!(*sent.lock().unwrap(), ["fsj", "z/", "fsj"]);
+
+        // no sign flagspace until the first match
+        let (mut r2p, _) = mock(&[
+            ("fsj", r#"[{"name":"symbols","count":30}]"#),
+            (
+                "fsj",
+                r#"[{"name":"symbols","count":30},{"name":"sign","count":1}]"#,
+            ),
+        ]);
+        assert_eq!(r2p.apply_signatures().unwrap(), 1);
+    }
+
+    #[test]
+    fn resolve_names_from_the_closest_flag() {
+        let fj = r#"[{"name":"main","offset":8192,"size":10},
+            {"name":"entry0","offset":4096,"size":38}]"#;
+        let (mut r2p, sent) = mock(&[("fj", fj)]);
+        let names = r2p
+            .resolve_names(&[0x1000, 0x1010, 0x2000, 0x2004, 0x10])
+            .unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["fj"]);
+        assert_eq!(
+            names,
+            [
+                Some("entry0".to_owned()),
+                Some("entry0 + 16".to_owned()),
+                Some("main".to_owned()),
+                Some("main + 4".to_owned()),
+                None,
+            ]
+        );
+    }
+}
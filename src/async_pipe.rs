@@ -0,0 +1,414 @@
+//! Asynchronous counterpart of `R2Pipe`, backed by tokio.
+//!
+//! Only the spawn, TCP and Unix socket transports are available. The API mirrors the
+//! blocking one: each command is written and its reply read up to the NUL
+//! terminator, one command in flight at a time per pipe.
+
+#[cfg(feature = "json")]
+use crate::r2pipe::parse_json;
+use crate::r2pipe::{
+    check_cmd, end_reply, into_string, process_result, spawn_error, R2PipeSpawnOptions, ReplyEnd,
+    EXIT_TIMEOUT, NO_HANDSHAKE,
+};
+use crate::{Error, Result};
+
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde_json::Value;
+
+use std::mem;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::time::timeout;
+
+/// Stores descriptors to the spawned r2 process.
+pub struct AsyncR2PipeSpawn {
+    child: Child,
+    read: BufReader<ChildStdout>,
+    /// `None` once closed.
+    write: Option<ChildStdin>,
+    /// Everything r2 wrote to stderr, when capturing was requested.
+    stderr: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+/// Stores the connection to the r2 process.
+pub struct AsyncR2PipeTcp {
+    /// `None` after an error, until the next command connects again.
+    stream: Option<BufReader<TcpStream>>,
+    /// Where `stream` was connected, to reconnect to.
+    addrs: Vec<SocketAddr>,
+    /// Whether a reply was read through `stream`, see `exchange`.
+    answered: bool,
+}
+
+/// Stores the connection to an r2 server listening on a Unix socket.
+#[cfg(unix)]
+#[cfg_attr(doc_cfg, doc(cfg(unix)))]
+pub struct AsyncR2PipeUnix {
+    /// `None` after an error, until the next command connects again.
+    stream: Option<BufReader<UnixStream>>,
+    path: PathBuf,
+    /// Whether a reply was read through `stream`, see `exchange`.
+    answered: bool,
+}
+
+/// Provides abstraction between the asynchronous invocation methods.
+pub enum AsyncR2Pipe {
+    Pipe(AsyncR2PipeSpawn),
+    Tcp(AsyncR2PipeTcp),
+    #[cfg(unix)]
+    #[cfg_attr(doc_cfg, doc(cfg(unix)))]
+    Unix(AsyncR2PipeUnix),
+}
+
+/// Writes `cmd` to `stream` and reads its reply, which may end with the
+/// connection like for `R2PipeTcp`, see `end_reply`. `stream` is dropped
+/// when it can not carry the next command, which then connects again.
+async fn exchange<S>(
+    stream: &mut Option<BufReader<S>>,
+    answered: &mut bool,
+    cmd: &str,
+) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let conn = stream.as_mut().ok_or(Error::ConnectionClosed)?;
+    let cmd = cmd.to_owned() + "\n";
+    let mut res: Vec<u8> = Vec::new();
+    let sent = async {
+        conn.get_mut().write_all(cmd.as_bytes()).await?;
+        conn.read_until(0u8, &mut res).await
+    };
+    if let Err(e) = sent.await {
+        // part of the reply may still come, it must not be taken for the
+        // one of the next command
+        *stream = None;
+        return Err(e.into());
+    }
+    match end_reply(&mut res, 0, *answered) {
+        ReplyEnd::Terminated => *answered = true,
+        ReplyEnd::Closed => *stream = None,
+        ReplyEnd::Lost => {
+            *stream = None;
+            return Err(Error::ConnectionClosed);
+        }
+    }
+    process_result(&cmd, res, 0)
+}
+
+impl AsyncR2Pipe {
+    /// Creates a new AsyncR2PipeSpawn, see `R2Pipe::spawn`.
+    pub async fn spawn<T: AsRef<str>>(
+        name: T,
+        opts: Option<R2PipeSpawnOptions>,
+    ) -> Result<AsyncR2Pipe> {
+        let mut opts = opts.unwrap_or_default();
+        if opts.exepath.is_empty() {
+            opts.exepath = "r2".to_owned();
+        }
+        let mut command = Command::new(&opts.exepath);
+        command.args(opts.r2_args()).arg(name.as_ref());
+        command.envs(opts.env.iter().map(|(k, v)| (k, v)));
+        if let Some(ref dir) = opts.cwd {
+            command.current_dir(dir);
+        }
+        if opts.capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| spawn_error(opts.exepath.as_ref(), e))?;
+
+        // If stdin/stdout is not available, hard error
+        let write = child.stdin.take().unwrap();
+        let mut read = BufReader::new(child.stdout.take().unwrap());
+
+        // r2 may write more than a pipe holds, so stderr is drained all along
+        let stderr = child.stderr.take().map(|mut serr| {
+            let buf = Arc::new(Mutex::new(Vec::new()));
+            let sink = Arc::clone(&buf);
+            let handle = tokio::spawn(async move {
+                let mut chunk = [0u8; 4096];
+                while let Ok(n) = serr.read(&mut chunk).await {
+                    if n == 0 {
+                        break;
+                    }
+                    sink.lock().unwrap().extend_from_slice(&chunk[..n]);
+                }
+            });
+            (buf, handle)
+        });
+
+        // flush out everything up to the initial null byte.
+        if !opts.skip_handshake {
+            let mut banner = Vec::new();
+            read.read_until(0u8, &mut banner).await?;
+            if banner.last() != Some(&0) {
+                // r2 may still run, eg. a wrapper which exited without it
+                let _ = child.kill().await;
+                if let Some((buf, handle)) = stderr {
+                    // r2 gave up before the session started, the reason is on stderr
+                    let _ = handle.await;
+                    let text = String::from_utf8_lossy(&buf.lock().unwrap())
+                        .trim()
+                        .to_owned();
+                    return Err(Error::StartupFailed(text));
+                }
+                return Err(Error::StartupFailed(NO_HANDSHAKE.to_owned()));
+            }
+        }
+
+        Ok(AsyncR2Pipe::Pipe(AsyncR2PipeSpawn {
+            child,
+            read,
+            write: Some(write),
+            stderr: stderr.map(|(buf, _)| buf),
+        }))
+    }
+
+    /// Creates a new AsyncR2PipeTcp, see `R2Pipe::tcp`.
+    ///
+    /// The connection is kept open for every command. When a command fails
+    /// because of it, the command is not run again but the next one opens a
+    /// new connection to the same address.
+    pub async fn tcp<A: ToSocketAddrs>(addr: A) -> Result<AsyncR2Pipe> {
+        let addrs: Vec<_> = lookup_host(addr).await?.collect();
+        let stream = TcpStream::connect(&addrs[..]).await?;
+        Ok(AsyncR2Pipe::Tcp(AsyncR2PipeTcp {
+            stream: Some(BufReader::new(stream)),
+            addrs,
+            answered: false,
+        }))
+    }
+
+    /// Creates a new AsyncR2PipeUnix, see `R2Pipe::unix`. It reconnects like
+    /// `AsyncR2Pipe::tcp`.
+    #[cfg(unix)]
+    #[cfg_attr(doc_cfg, doc(cfg(unix)))]
+    pub async fn unix<P: AsRef<Path>>(path: P) -> Result<AsyncR2Pipe> {
+        let stream = UnixStream::connect(path.as_ref()).await?;
+        Ok(AsyncR2Pipe::Unix(AsyncR2PipeUnix {
+            stream: Some(BufReader::new(stream)),
+            path: path.as_ref().to_owned(),
+            answered: false,
+        }))
+    }
+
+    pub async fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd).await?)
+    }
+
+    /// Runs `cmd` and returns its output as is, see `R2Pipe::cmd_bytes`.
+    pub async fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        check_cmd(cmd.trim())?;
+        match *self {
+            AsyncR2Pipe::Pipe(ref mut x) => x.cmd_bytes(cmd.trim()).await,
+            AsyncR2Pipe::Tcp(ref mut x) => x.cmd_bytes(cmd.trim()).await,
+            #[cfg(unix)]
+            AsyncR2Pipe::Unix(ref mut x) => x.cmd_bytes(cmd.trim()).await,
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub async fn cmdj(&mut self, cmd: &str) -> Result<Value> {
+        self.cmd_typed(cmd).await
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Runs `cmd` and deserializes its JSON output into `T`.
+    pub async fn cmd_typed<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<T> {
+        let res = self.cmd_bytes(cmd).await?;
+        parse_json(cmd, &res)
+    }
+
+    /// Returns what a spawned r2 has written to stderr so far, see
+    /// `R2Pipe::last_stderr`.
+    pub fn last_stderr(&self) -> Option<String> {
+        match *self {
+            AsyncR2Pipe::Pipe(ref x) => x.last_stderr(),
+            _ => None,
+        }
+    }
+
+    pub async fn close(&mut self) {
+        match *self {
+            AsyncR2Pipe::Pipe(ref mut x) => x.close().await,
+            AsyncR2Pipe::Tcp(ref mut x) => x.close().await,
+            #[cfg(unix)]
+            AsyncR2Pipe::Unix(ref mut x) => x.close().await,
+        }
+    }
+}
+
+impl AsyncR2PipeSpawn {
+    pub async fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd).await?)
+    }
+
+    pub async fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        let cmd = cmd.to_owned() + "\n";
+        let write = self.write.as_mut().ok_or(Error::NoSession)?;
+        write.write_all(cmd.as_bytes()).await?;
+
+        let mut res: Vec<u8> = Vec::new();
+        self.read.read_until(0u8, &mut res).await?;
+        process_result(&cmd, res, 0)
+    }
+
+    /// Returns what r2 has written to stderr so far, see
+    /// `R2PipeSpawn::last_stderr`.
+    pub fn last_stderr(&self) -> Option<String> {
+        let buf = self.stderr.as_ref()?.lock().unwrap();
+        Some(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Returns and forgets what r2 has written to stderr, see
+    /// `R2PipeSpawn::take_stderr`.
+    pub fn take_stderr(&mut self) -> Option<String> {
+        let mut buf = self.stderr.as_ref()?.lock().unwrap();
+        Some(String::from_utf8_lossy(&mem::take(&mut *buf)).into_owned())
+    }
+
+    /// Sends `q!` and closes r2's stdin without waiting for a reply, then
+    /// waits for r2 to exit, killing it if it is still running after a
+    /// moment, see `R2PipeSpawn::close`. Later commands fail with
+    /// `Error::NoSession`.
+    pub async fn close(&mut self) {
+        if let Some(mut w) = self.write.take() {
+            let quit = async {
+                w.write_all(b"q!\n").await?;
+                w.flush().await
+            };
+            let _ = timeout(EXIT_TIMEOUT, quit).await;
+        }
+        if timeout(EXIT_TIMEOUT, self.child.wait()).await.is_err() {
+            let _ = self.child.kill().await;
+        }
+    }
+}
+
+impl AsyncR2PipeTcp {
+    pub async fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd).await?)
+    }
+
+    pub async fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        if self.stream.is_none() {
+            let stream = TcpStream::connect(&self.addrs[..]).await?;
+            self.stream = Some(BufReader::new(stream));
+            self.answered = false;
+        }
+        exchange(&mut self.stream, &mut self.answered, cmd).await
+    }
+
+    pub async fn close(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.get_mut().shutdown().await;
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsyncR2PipeUnix {
+    pub async fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd).await?)
+    }
+
+    pub async fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        if self.stream.is_none() {
+            let stream = UnixStream::connect(&self.path).await?;
+            self.stream = Some(BufReader::new(stream));
+            self.answered = false;
+        }
+        exchange(&mut self.stream, &mut self.answered, cmd).await
+    }
+
+    pub async fn close(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.get_mut().shutdown().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, Write};
+    use std::thread;
+
+    #[tokio::test]
+    async fn tcp_against_stock_server() {
+        // like `r2 -c '.:9080'`, one command per connection, ended by closing it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut cmds = Vec::new();
+            for reply in ["Hello World\n", ""] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = std::io::BufReader::new(stream);
+                let mut cmd = String::new();
+                stream.read_line(&mut cmd).unwrap();
+                cmds.push(cmd);
+                stream.get_mut().write_all(reply.as_bytes()).unwrap();
+            }
+            cmds
+        });
+        let mut r2p = AsyncR2Pipe::tcp(addr).await.unwrap();
+        assert_eq!(r2p.cmd("?e Hello World").await.unwrap(), "Hello World\n");
+        assert_eq!(r2p.cmd("s 0x1000").await.unwrap(), "");
+        assert_eq!(server.join().unwrap(), ["?e Hello World\n", "s 0x1000\n"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_handshake_reaps_r2() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let script = dir.join(format!("r2pipe-async-nohandshake-{}", id));
+        let pidfile = dir.join(format!("r2pipe-async-nohandshake-{}.pid", id));
+        // closes its stdout without the handshake, but keeps running
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho $$ >{}\nexec >&-\nexec sleep 10\n",
+                pidfile.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let opts = R2PipeSpawnOptions::builder()
+            .exepath(script.to_str().unwrap())
+            .build();
+        match AsyncR2Pipe::spawn("-", Some(opts)).await {
+            Err(Error::StartupFailed(ref why)) => assert_eq!(why, NO_HANDSHAKE),
+            res => panic!("{:?}", res.err()),
+        }
+        let pid: libc::pid_t = std::fs::read_to_string(&pidfile)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        // neither running nor a zombie anymore
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&pidfile);
+    }
+}
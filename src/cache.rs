@@ -0,0 +1,134 @@
+//! Least recently used cache of command outputs.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Prefixes of the commands whose output is cached: the `p` (print), `i`
+/// (binary info) and `x` (hexdump) families, which only read state.
+///
+/// Any other command may seek, write, analyze or reconfigure the session,
+/// so running it drops every cached entry. Commands chaining several
+/// commands with `;` or piping with `|` are never cached either.
+///
+/// Without an `@` the output of these depends on the current seek and
+/// block size, which the cache does not track: it relies on `s` and `b`,
+/// like every other command, clearing it.
+pub const CACHEABLE_PREFIXES: &[&str] = &["p", "i", "x"];
+
+pub(crate) fn is_cacheable(cmd: &str) -> bool {
+    !cmd.contains(';')
+        && !cmd.contains('|')
+        && CACHEABLE_PREFIXES.iter().any(|p| cmd.starts_with(p))
+}
+
+pub(crate) struct Cache {
+    capacity: usize,
+    entries: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl Cache {
+    pub(crate) fn new(capacity: usize) -> Cache {
+        Cache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, cmd: &str) -> Option<String> {
+        let res = self.entries.get(cmd)?.clone();
+        self.touch(cmd);
+        Some(res)
+    }
+
+    pub(crate) fn insert(&mut self, cmd: &str, res: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self
+            .entries
+            .insert(cmd.to_owned(), res.to_owned())
+            .is_some()
+        {
+            self.touch(cmd);
+            return;
+        }
+        self.order.push_back(cmd.to_owned());
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn touch(&mut self, cmd: &str) {
+        if let Some(pos) = self.order.iter().position(|c| c == cmd) {
+            if let Some(c) = self.order.remove(pos) {
+                self.order.push_back(c);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    #[test]
+    fn cacheable_commands() {
+        assert!(is_cacheable("pd 10"));
+        assert!(is_cacheable("ij"));
+        assert!(is_cacheable("x 16 @ 0x1000"));
+        assert!(!is_cacheable("s 0x1000"));
+        assert!(!is_cacheable("b 32"));
+        assert!(!is_cacheable("aa"));
+        assert!(!is_cacheable("pd 1;s+"));
+        assert!(!is_cacheable("px 16|grep ff"));
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = Cache::new(2);
+        cache.insert("pd 1", "a");
+        cache.insert("pd 2", "b");
+        assert_eq!(cache.get("pd 1").as_deref(), Some("a"));
+        cache.insert("pd 3", "c");
+        // pd 2 was used last the longest ago
+        assert_eq!(cache.get("pd 2"), None);
+        assert_eq!(cache.get("pd 1").as_deref(), Some("a"));
+        assert_eq!(cache.get("pd 3").as_deref(), Some("c"));
+        // replacing an entry uses it
+        cache.insert("pd 1", "A");
+        cache.insert("pd 4", "d");
+        assert_eq!(cache.get("pd 3"), None);
+        assert_eq!(cache.get("pd 1").as_deref(), Some("A"));
+        cache.clear();
+        assert_eq!(cache.get("pd 1"), None);
+    }
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let mut cache = Cache::new(0);
+        cache.insert("pd 1", "a");
+        assert_eq!(cache.get("pd 1"), None);
+    }
+
+    #[test]
+    fn seek_and_block_size_clear_the_cache() {
+        let (mut r2p, sent) = mock(&[]);
+        r2p.enable_cache(8);
+        for cmd in ["px", "px", "s 0x10", "px", "b 32", "px", "pd 1;s+", "px"] {
+            r2p.cmd(cmd).unwrap();
+        }
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["px", "s 0x10", "px", "b 32", "px", "pd 1;s+", "px"]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Helpers to query and change the configuration of the r2 session.
+
+use crate::{R2Pipe, Result};
+
+/// Issues `cmd` on the wrapped pipe when dropped, used to restore a setting
+/// once a scoped operation is over, whether it succeeded, failed or panicked.
+pub(crate) struct Restore<'a> {
+    pub(crate) r2p: &'a mut R2Pipe,
+    pub(crate) cmd: String,
+}
+
+impl<'a> Drop for Restore<'a> {
+    fn drop(&mut self) {
+        let _ = self.r2p.cmd(&self.cmd);
+    }
+}
+
+impl R2Pipe {
+    /// Returns the value of the `key` eval variable (`e key`).
+    pub fn get_config(&mut self, key: &str) -> Result<String> {
+        Ok(self.cmd(&format!("e {}", key))?.trim().to_owned())
+    }
+
+    /// Sets the `key` eval variable to `value` (`e key=value`).
+    pub fn set_config(&mut self, key: &str, value: &str) -> Result<()> {
+        self.cmd(&format!("e {}={}", key, value))?;
+        Ok(())
+    }
+
+    /// Returns whether the session is configured as big endian.
+    pub fn big_endian(&mut self) -> Result<bool> {
+        Ok(self.get_config("cfg.bigendian")? == "true")
+    }
+
+    /// Configures the session as big (`true`) or little (`false`) endian.
+    pub fn set_endian(&mut self, big: bool) -> Result<()> {
+        self.set_config("cfg.bigendian", if big { "true" } else { "false" })
+    }
+
+    /// Runs `f` with the session temporarily configured as big or little
+    /// endian. The previous endianness is restored once `f` returns, even if
+    /// it returned an error.
+    pub fn with_endian<T, F>(&mut self, big: bool, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut R2Pipe) -> Result<T>,
+    {
+        let prev = self.get_config("cfg.bigendian")?;
+        self.set_endian(big)?;
+        let guard = Restore {
+            r2p: self,
+            cmd: format!("e cfg.bigendian={}", prev),
+        };
+        f(guard.r2p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::mock;
+    use crate::{Error, Result};
+
+    #[test]
+    fn get_and_set_config() {
+        let (mut r2p, sent) = mock(&[("e asm.bits", "64\n"), ("e cfg.bigendian", "true\n")]);
+        assert_eq!(r2p.get_config("asm.bits").unwrap(), "64");
+        r2p.set_config("asm.bits", "32").unwrap();
+        assert!(r2p.big_endian().unwrap());
+        r2p.set_endian(false).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                "e asm.bits",
+                "e asm.bits=32",
+                "e cfg.bigendian",
+                "e cfg.bigendian=false"
+            ]
+        );
+    }
+
+    #[test]
+    fn with_endian_restores_the_endianness() {
+        let (mut r2p, sent) = mock(&[("e cfg.bigendian", "false\n"), ("pv4", "0x1\n")]);
+        assert_eq!(
+            r2p.with_endian(true, |r2p| r2p.cmd("pv4")).unwrap(),
+            "0x1\n"
+        );
+        let res: Result<()> = r2p.with_endian(true, |_| Err(Error::NoDecompiler));
+        assert!(matches!(res, Err(Error::NoDecompiler)));
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                "e cfg.bigendian",
+                "e cfg.bigendian=true",
+                "pv4",
+                "e cfg.bigendian=false",
+                "e cfg.bigendian",
+                "e cfg.bigendian=true",
+                "e cfg.bigendian=false",
+            ]
+        );
+    }
+}
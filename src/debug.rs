@@ -0,0 +1,193 @@
+//! Helpers to drive the r2 debugger: breakpoints, stepping, registers and
+//! memory of the debuggee.
+
+use crate::r2pipe::check_plain;
+use crate::{R2Pipe, Result};
+
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+/// A breakpoint, as listed by `dbj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub addr: u64,
+    #[serde(default)]
+    pub enabled: bool,
+    /// Number of times it was hit.
+    #[serde(default)]
+    pub hits: u64,
+}
+
+/// Debugger commands of a pipe, see `R2Pipe::debug`.
+///
+/// r2 must be debugging a process, eg. spawned with `-d`. The outputs of
+/// `continue_` and the stepping commands are what r2 printed, such as the
+/// breakpoint hit or the signal received.
+///
+/// ```no_run
+/// # use r2pipe::{R2Pipe, R2PipeSpawnOptions};
+/// # fn main() -> r2pipe::Result<()> {
+/// let opts = R2PipeSpawnOptions::builder().arg("-d").build();
+/// let mut r2p = R2Pipe::spawn("/bin/ls", Some(opts))?;
+/// let mut dbg = r2p.debug();
+/// dbg.set_breakpoint(0x401000)?;
+/// dbg.continue_()?;
+/// println!("rsp = {:#x}", dbg.registers()?["rsp"]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Debugger<'a> {
+    r2p: &'a mut R2Pipe,
+}
+
+impl<'a> Debugger<'a> {
+    /// Sets a breakpoint at `addr` (`db addr`).
+    pub fn set_breakpoint(&mut self, addr: u64) -> Result<()> {
+        self.r2p.cmd(&format!("db {:#x}", addr))?;
+        Ok(())
+    }
+
+    /// Removes the breakpoint at `addr` (`db- addr`).
+    pub fn remove_breakpoint(&mut self, addr: u64) -> Result<()> {
+        self.r2p.cmd(&format!("db- {:#x}", addr))?;
+        Ok(())
+    }
+
+    /// Returns the breakpoints (`dbj`).
+    pub fn breakpoints(&mut self) -> Result<Vec<Breakpoint>> {
+        self.r2p.cmd_typed("dbj")
+    }
+
+    /// Runs the debuggee until a breakpoint, a signal or its exit (`dc`).
+    pub fn continue_(&mut self) -> Result<String> {
+        self.r2p.cmd("dc")
+    }
+
+    /// Runs the debuggee until `addr` (`dcu addr`).
+    pub fn continue_until(&mut self, addr: u64) -> Result<String> {
+        self.r2p.cmd(&format!("dcu {:#x}", addr))
+    }
+
+    /// Executes one instruction (`ds`).
+    pub fn step(&mut self) -> Result<String> {
+        self.r2p.cmd("ds")
+    }
+
+    /// Executes one instruction, stepping over calls (`dso`).
+    pub fn step_over(&mut self) -> Result<String> {
+        self.r2p.cmd("dso")
+    }
+
+    /// Returns the general purpose registers and their values (`drj`).
+    pub fn registers(&mut self) -> Result<HashMap<String, u64>> {
+        self.r2p.cmd_typed("drj")
+    }
+
+    /// Sets the register `name` to `value` (`dr name=value`). Fails with
+    /// `Error::UnsafeCommand` when `name` is not a plain word.
+    pub fn set_register(&mut self, name: &str, value: u64) -> Result<()> {
+        check_plain(name, true)?;
+        self.r2p.cmd(&format!("dr {}={:#x}", name, value))?;
+        Ok(())
+    }
+
+    /// Reads `len` bytes of the debuggee's memory at `addr`
+    /// (`pxj len @ addr`).
+    pub fn read_mem(&mut self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        self.r2p.cmd_typed(&format!("pxj {} @ {:#x}", len, addr))
+    }
+}
+
+impl R2Pipe {
+    /// Returns the debugger commands of the pipe.
+    pub fn debug(&mut self) -> Debugger<'_> {
+        Debugger { r2p: self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+    use crate::Error;
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let (mut r2p, sent) = mock(&[
+            (
+                "dbj",
+                r#"[{"addr":4198400,"size":1,"perm":"--x","hits":2,"trace":false,
+                "enabled":true,"data":"","cond":""},{"addr":4198416,"enabled":false}]"#,
+            ),
+            ("dcu 0x401010", "hit breakpoint at: 0x401010\n"),
+        ]);
+        let mut dbg = r2p.debug();
+        dbg.set_breakpoint(0x401000).unwrap();
+        dbg.remove_breakpoint(0x401000).unwrap();
+        assert_eq!(
+            dbg.breakpoints().unwrap(),
+            [
+                Breakpoint {
+                    addr: 0x401000,
+                    enabled: true,
+                    hits: 2,
+                },
+                Breakpoint {
+                    addr: 0x401010,
+                    enabled: false,
+                    hits: 0,
+                },
+            ]
+        );
+        assert_eq!(
+            dbg.continue_until(0x401010).unwrap(),
+            "hit breakpoint at: 0x401010\n"
+        );
+        dbg.continue_().unwrap();
+        dbg.step().unwrap();
+        dbg.step_over().unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                "db 0x401000",
+                "db- 0x401000",
+                "dbj",
+                "dcu 0x401010",
+                "dc",
+                "ds",
+                "dso"
+            ]
+        );
+    }
+
+    #[test]
+    fn registers() {
+        let (mut r2p, sent) = mock(&[(
+            "drj",
+            r#"{"rax":0,"rbx":140737488346600,"rip":4198400,"rflags":518}"#,
+        )]);
+        let mut dbg = r2p.debug();
+        let regs = dbg.registers().unwrap();
+        assert_eq!(regs.len(), 4);
+        assert_eq!(regs["rip"], 0x401000);
+        assert_eq!(regs["rbx"], 0x7fff_ffff_dde8);
+        dbg.set_register("rax", 0x2a).unwrap();
+        assert!(matches!(
+            dbg.set_register("rax;ls", 1),
+            Err(Error::UnsafeCommand(_))
+        ));
+        assert_eq!(*sent.lock().unwrap(), ["drj", "dr rax=0x2a"]);
+    }
+
+    #[test]
+    fn read_mem() {
+        let (mut r2p, sent) = mock(&[("pxj 4 @ 0x7ffe0000", "[127,69,76,70]")]);
+        let mut dbg = r2p.debug();
+        assert_eq!(dbg.read_mem(0x7ffe_0000, 4).unwrap(), b"\x7fELF");
+        assert!(dbg.read_mem(0x7ffe_0000, 0).unwrap().is_empty());
+        assert_eq!(*sent.lock().unwrap(), ["pxj 4 @ 0x7ffe0000"]);
+    }
+}
@@ -1,10 +1,8 @@
+use crate::r2pipe::TransportKind;
 use std::sync::mpsc::{RecvError, SendError, TryRecvError};
 use std::{io, str};
 use thiserror::Error;
 
-#[cfg(feature = "http")]
-use reqwest;
-
 /// Custom `Error` for r2pipe.rs.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -16,15 +14,93 @@ pub enum Error {
     #[error("No open session")]
     NoSession,
 
-    /// Response had invalid/missing JSON.
-    #[error("Empty response from JSON")]
+    /// Nothing at all was received in reply to a command, or the output
+    /// of a command expected to print JSON is empty.
+    #[error("Empty response")]
     EmptyResponse,
 
     /// Incorrect number of arguments, or incorrect format.
     #[error("Argument mismatch")]
     ArgumentMismatch,
 
+    /// The r2 executable could not be found, eg. it is not on `PATH`.
+    #[error("r2 executable not found: {0}")]
+    ExecutableNotFound(String),
+
+    /// r2 exited before the session started, with what it wrote to stderr
+    /// when it is captured.
+    #[error("r2 failed to start: {0}")]
+    StartupFailed(String),
+
+    /// The stream ended in the middle of the reply to `cmd`, after
+    /// `partial`.
+    #[error("Unexpected end of stream running `{cmd}` after {}", shorten(.partial))]
+    UnexpectedEof { cmd: String, partial: String },
+
+    /// The command contains a newline or a NUL byte.
+    #[error("Invalid command {0:?}: contains a newline or NUL byte")]
+    InvalidCommand(String),
+
+    /// A command or argument given to `cmd_at` or `cmd_fmt` contains
+    /// characters r2 interprets, such as `;`, `|` or `@`.
+    #[error("Unsafe command {0:?}: contains characters interpreted by r2")]
+    UnsafeCommand(String),
+
+    /// The connection was closed by the other end or after an error.
+    #[error("Connection closed")]
+    ConnectionClosed,
+
+    /// No reply was received within the configured timeout.
+    #[error("Command timed out")]
+    Timeout,
+
+    /// A spawned r2 did not reply within the configured timeout and was
+    /// killed. `still_running` tells whether it was still running when the
+    /// timeout fired, ie. busy or stuck, rather than gone with its output
+    /// left open, eg. by a child process.
+    #[error("Command timed out, r2 {}", if *.still_running { "was still running" } else { "had exited" })]
+    CommandTimeout { still_running: bool },
+
+    /// The operation can not be performed over this transport.
+    #[error("Operation not supported by the transport")]
+    Unsupported,
+
+    /// Response could not be parsed as the expected value.
+    #[error("Unexpected response: {0}")]
+    UnexpectedResponse(String),
+
+    /// r2 replied to `cmd` with an error message, in strict mode, or a
+    /// command did not have the expected effect. `stderr` is what a spawned
+    /// r2 wrote there, when it is captured.
+    #[error("Command `{cmd}` failed on {transport} transport: {}", shorten(.output))]
+    CommandFailed {
+        cmd: String,
+        transport: TransportKind,
+        stderr: Option<String>,
+        output: String,
+    },
+
+    /// Data written to the session could not be read back.
+    #[error("Write failed at {0:#x}")]
+    WriteFailed(u64),
+
+    /// No decompiler produced output, perhaps none is installed.
+    #[error("No decompiler available")]
+    NoDecompiler,
+
+    /// The output of `cmd` is not the JSON expected; `raw` is the whole
+    /// output, shortened when displayed.
+    #[cfg(feature = "json")]
+    #[error("Invalid JSON from `{cmd}`: {source} in {}", shorten(.raw))]
+    JsonParse {
+        cmd: String,
+        raw: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
     /// An error occurred inside of serde.
+    #[cfg(feature = "json")]
     #[error("Serde deserialization error")]
     SerdeError(#[from] serde_json::Error),
 
@@ -44,8 +120,42 @@ pub enum Error {
     #[error("Send channel data error")]
     ChannelSendError(#[from] SendError<String>),
 
+    /// The queue of a bounded worker is full, the command was not sent.
+    #[error("Channel full, command not sent: {0}")]
+    ChannelFull(String),
+
+    /// A worker thread of `R2Pipe::threads` panicked.
+    #[error("Pipe thread panicked: {0}")]
+    WorkerPanicked(String),
+
+    /// No reply is expected for this command id from the pipe thread.
+    #[error("No pending reply for command #{0}")]
+    UnknownCommand(u64),
+
     /// Error during reqwest operation.
     #[cfg(feature = "http")]
     #[error("Reqwest error")]
     Reqwest(#[from] reqwest::Error),
+
+    /// Error during WebSocket operation.
+    #[cfg(feature = "ws")]
+    #[error("WebSocket error")]
+    WebSocket(#[source] Box<tungstenite::Error>),
+}
+
+#[cfg(feature = "ws")]
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Error {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
+/// Longest part of a response shown in error messages.
+const SHOWN_LEN: usize = 200;
+
+fn shorten(raw: &str) -> String {
+    match raw.char_indices().nth(SHOWN_LEN) {
+        Some((end, _)) => format!("{:?}...", &raw[..end]),
+        None => format!("{:?}", raw),
+    }
 }
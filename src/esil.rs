@@ -0,0 +1,172 @@
+//! Helpers to work with ESIL, the intermediate language used by r2.
+
+use crate::r2pipe::check_plain;
+use crate::{Error, R2Pipe, Result};
+
+use std::collections::HashMap;
+
+impl R2Pipe {
+    /// Returns the ESIL expression of the instruction at `addr`
+    /// (`aoj 1 @ addr`).
+    ///
+    /// An empty string is returned for instructions r2 can not lift.
+    pub fn esil_of(&mut self, addr: u64) -> Result<String> {
+        let json = self.cmdj(&format!("aoj 1 @ {:#x}", addr))?;
+        Ok(json[0]["esil"].as_str().unwrap_or_default().to_owned())
+    }
+
+    /// Returns the ESIL emulation commands of the pipe.
+    pub fn esil(&mut self) -> Esil<'_> {
+        Esil { r2p: self }
+    }
+}
+
+/// ESIL emulation commands of a pipe, see `R2Pipe::esil`.
+///
+/// The emulated registers are not the ones of a debugged process, the
+/// memory is the one of the session, with a stack mapped by `init_at`.
+///
+/// ```no_run
+/// # use r2pipe::R2Pipe;
+/// # fn main() -> r2pipe::Result<()> {
+/// let mut r2p = R2Pipe::spawn("/bin/ls", None)?;
+/// let mut esil = r2p.esil();
+/// esil.init_at(0x401000)?;
+/// esil.step(10)?;
+/// println!("rax = {:#x}", esil.reg("rax")?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Esil<'a> {
+    r2p: &'a mut R2Pipe,
+}
+
+impl<'a> Esil<'a> {
+    /// Sets the emulation up (`aei`), maps its stack (`aeim`) and moves the
+    /// program counter to `addr`.
+    pub fn init_at(&mut self, addr: u64) -> Result<()> {
+        self.r2p.cmd("aei")?;
+        self.r2p.cmd("aeim")?;
+        self.r2p.cmd(&format!("aer PC={:#x}", addr))?;
+        Ok(())
+    }
+
+    /// Emulates `n` instructions, in a single command repeating `aes`
+    /// (`{n}aes`).
+    pub fn step(&mut self, n: usize) -> Result<()> {
+        if n > 0 {
+            self.r2p.cmd(&format!("{}aes", n))?;
+        }
+        Ok(())
+    }
+
+    /// Emulates instructions until the program counter reaches `addr`
+    /// (`aesu addr`).
+    pub fn until(&mut self, addr: u64) -> Result<()> {
+        self.r2p.cmd(&format!("aesu {:#x}", addr))?;
+        Ok(())
+    }
+
+    /// Returns the value of the emulated register `name` (`aer name`), which
+    /// may be a role such as `PC` or `SP`.
+    pub fn reg(&mut self, name: &str) -> Result<u64> {
+        check_plain(name, true)?;
+        let res = self.r2p.cmd(&format!("aer {}", name))?;
+        let hex = res.trim();
+        u64::from_str_radix(hex.trim_start_matches("0x"), 16)
+            .map_err(|_| Error::UnexpectedResponse(hex.to_owned()))
+    }
+
+    /// Returns the emulated registers and their values (`aerj`).
+    pub fn regs(&mut self) -> Result<HashMap<String, u64>> {
+        self.r2p.cmd_typed("aerj")
+    }
+
+    /// Sets the emulated register `name` to `value` (`aer name=value`).
+    pub fn set_reg(&mut self, name: &str, value: u64) -> Result<()> {
+        check_plain(name, true)?;
+        self.r2p.cmd(&format!("aer {}={:#x}", name, value))?;
+        Ok(())
+    }
+
+    /// Reads `len` bytes at `addr`, see `R2Pipe::read_bytes`.
+    pub fn mem_read(&mut self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        self.r2p.read_bytes(addr, len)
+    }
+
+    /// Writes `data` at `addr`, see `R2Pipe::write_bytes`. Outside of the
+    /// stack, the file must be writable or `io.cache` enabled.
+    pub fn mem_write(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        self.r2p.write_bytes(addr, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    #[test]
+    fn init_and_step() {
+        let (mut r2p, sent) = mock(&[]);
+        let mut esil = r2p.esil();
+        esil.init_at(0x401000).unwrap();
+        esil.step(10).unwrap();
+        esil.step(0).unwrap();
+        esil.until(0x401020).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["aei", "aeim", "aer PC=0x401000", "10aes", "aesu 0x401020"]
+        );
+    }
+
+    #[test]
+    fn registers() {
+        let (mut r2p, sent) = mock(&[
+            ("aer rax", "0x0000002a\n"),
+            ("aer PC", "garbage\n"),
+            ("aerj", r#"{"rax":42,"rsp":1179648,"rip":4198400}"#),
+        ]);
+        let mut esil = r2p.esil();
+        assert_eq!(esil.reg("rax").unwrap(), 0x2a);
+        assert!(matches!(esil.reg("PC"), Err(Error::UnexpectedResponse(ref s)) if s == "garbage"));
+        assert!(matches!(esil.reg("rax rbx"), Err(Error::UnsafeCommand(_))));
+        let regs = esil.regs().unwrap();
+        assert_eq!(regs["rsp"], 0x12_0000);
+        assert_eq!(regs["rip"], 0x40_1000);
+        esil.set_reg("rsp", 0x100).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["aer rax", "aer PC", "aerj", "aer rsp=0x100"]
+        );
+    }
+
+    #[test]
+    fn memory() {
+        let (mut r2p, sent) = mock(&[("p8 2 @ 0x1000", "9090\n")]);
+        let mut esil = r2p.esil();
+        esil.mem_write(0x1000, &[0x90, 0x90]).unwrap();
+        assert_eq!(esil.mem_read(0x1000, 2).unwrap(), [0x90, 0x90]);
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["wx 9090 @ 0x1000", "p8 2 @ 0x1000", "p8 2 @ 0x1000"]
+        );
+    }
+
+    #[test]
+    fn esil_of_an_instruction() {
+        let (mut r2p, _) = mock(&[
+            (
+                "aoj 1 @ 0x1000",
+                r#"[{"opcode":"mov eax, 1","esil":"1,rax,=","addr":4096,"size":5}]"#,
+            ),
+            (
+                "aoj 1 @ 0x2000",
+                r#"[{"opcode":"invalid","addr":8192,"size":1}]"#,
+            ),
+        ]);
+        assert_eq!(r2p.esil_of(0x1000).unwrap(), "1,rax,=");
+        // r2 can not lift it and leaves the esil field out
+        assert_eq!(r2p.esil_of(0x2000).unwrap(), "");
+    }
+}
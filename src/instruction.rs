@@ -0,0 +1,269 @@
+//! Typed access to the instructions analyzed by r2.
+
+use crate::{Error, R2Pipe, Result};
+
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+/// An operand of an instruction, as reported in the `opex` field of `aoj`.
+///
+/// Only the explicit operands are listed; registers read or written
+/// implicitly (eg. `rsp` by `push`) are not part of `opex`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    /// A register, eg. `rax`.
+    Register { name: String, size: u64 },
+    /// An immediate value.
+    Immediate { value: i64, size: u64 },
+    /// A memory access to `segment:[base + index * scale + disp]`.
+    Memory {
+        segment: Option<String>,
+        base: Option<String>,
+        index: Option<String>,
+        scale: i64,
+        disp: i64,
+        size: u64,
+    },
+    /// An operand type r2 reports but which is not classified above.
+    Other { kind: String },
+}
+
+#[derive(Deserialize)]
+struct RawOperand {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    value: Value,
+    #[serde(default)]
+    segment: Option<String>,
+    #[serde(default)]
+    base: Option<String>,
+    #[serde(default)]
+    index: Option<String>,
+    #[serde(default)]
+    scale: i64,
+    #[serde(default)]
+    disp: i64,
+}
+
+fn to_i64(v: &Value) -> i64 {
+    v.as_i64()
+        .or_else(|| v.as_u64().map(|n| n as i64))
+        .unwrap_or(0)
+}
+
+impl From<RawOperand> for Operand {
+    fn from(op: RawOperand) -> Operand {
+        match op.kind.as_str() {
+            "reg" => Operand::Register {
+                name: op.value.as_str().unwrap_or_default().to_owned(),
+                size: op.size,
+            },
+            "imm" => Operand::Immediate {
+                value: to_i64(&op.value),
+                size: op.size,
+            },
+            "mem" => Operand::Memory {
+                // r2 reports the absence of a register as an empty name
+                segment: op.segment.filter(|s| !s.is_empty()),
+                base: op.base.filter(|s| !s.is_empty()),
+                index: op.index.filter(|s| !s.is_empty()),
+                scale: op.scale,
+                disp: op.disp,
+                size: op.size,
+            },
+            _ => Operand::Other { kind: op.kind },
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Opex {
+    #[serde(default)]
+    operands: Vec<RawOperand>,
+}
+
+/// An analyzed instruction, as listed by `aoj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    #[serde(alias = "offset")]
+    pub addr: u64,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub bytes: String,
+    #[serde(default)]
+    pub opcode: String,
+    #[serde(default)]
+    pub disasm: String,
+    #[serde(default)]
+    pub mnemonic: String,
+    #[serde(default)]
+    pub esil: String,
+    #[serde(rename = "type", default)]
+    pub type_: String,
+    #[serde(default)]
+    pub jump: Option<u64>,
+    #[serde(default)]
+    pub fail: Option<u64>,
+    #[serde(rename = "opex", default, deserialize_with = "de_operands")]
+    pub operands: Vec<Operand>,
+}
+
+fn de_operands<'de, D>(d: D) -> std::result::Result<Vec<Operand>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let opex: Opex = serde::Deserialize::deserialize(d)?;
+    Ok(opex.operands.into_iter().map(Operand::from).collect())
+}
+
+/// The part of `pdfj` listing the instructions of the function.
+#[derive(Deserialize)]
+struct RawFunction {
+    #[serde(default)]
+    ops: Vec<Instruction>,
+}
+
+/// Fills the mnemonic of instructions listed by `pd`, which only report the
+/// opcode, with its first word.
+fn with_mnemonics(mut ops: Vec<Instruction>) -> Vec<Instruction> {
+    for op in ops.iter_mut().filter(|op| op.mnemonic.is_empty()) {
+        op.mnemonic = op
+            .opcode
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+    }
+    ops
+}
+
+impl R2Pipe {
+    /// Returns the analyzed instruction at `addr` (`aoj 1 @ addr`).
+    /// `Error::UnexpectedResponse` is returned when r2 listed none, eg. at
+    /// an unmapped address.
+    pub fn instruction_at(&mut self, addr: u64) -> Result<Instruction> {
+        let mut json = self.cmdj(&format!("aoj 1 @ {:#x}", addr))?;
+        match json.as_array_mut().and_then(|a| a.get_mut(0)) {
+            Some(op) => Ok(serde_json::from_value(op.take())?),
+            None => Err(Error::UnexpectedResponse(json.to_string())),
+        }
+    }
+
+    /// Disassembles `n` instructions from `addr` (`pdj n @ addr`).
+    ///
+    /// Unlike `instruction_at`, operands are not listed.
+    pub fn disassemble_at(&mut self, addr: u64, n: usize) -> Result<Vec<Instruction>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let ops = self.cmd_typed(&format!("pdj {} @ {:#x}", n, addr))?;
+        Ok(with_mnemonics(ops))
+    }
+
+    /// Disassembles the function containing `addr` (`pdfj @ addr`), which
+    /// must have been analyzed, eg. with `af` or `aa`.
+    ///
+    /// Operands are not listed.
+    pub fn disassemble_function(&mut self, addr: u64) -> Result<Vec<Instruction>> {
+        let func: RawFunction = self.cmd_typed(&format!("pdfj @ {:#x}", addr))?;
+        Ok(with_mnemonics(func.ops))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    fn operands(opex: &str) -> Vec<Operand> {
+        let op = format!(r#"{{"addr":4096,"opex":{}}}"#, opex);
+        serde_json::from_str::<Instruction>(&op).unwrap().operands
+    }
+
+    #[test]
+    fn register_and_immediate() {
+        let ops = operands(
+            r#"{"operands":[{"type":"reg","value":"rax","size":8},
+            {"type":"imm","value":18446744073709551615,"size":8}]}"#,
+        );
+        assert_eq!(
+            ops,
+            [
+                Operand::Register {
+                    name: "rax".to_owned(),
+                    size: 8
+                },
+                Operand::Immediate { value: -1, size: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_with_segment_override() {
+        let ops = operands(
+            r#"{"operands":[{"type":"mem","segment":"fs","base":"","index":"rcx",
+            "scale":4,"disp":40,"size":8}]}"#,
+        );
+        assert_eq!(
+            ops,
+            [Operand::Memory {
+                segment: Some("fs".to_owned()),
+                base: None,
+                index: Some("rcx".to_owned()),
+                scale: 4,
+                disp: 40,
+                size: 8,
+            }]
+        );
+    }
+
+    #[test]
+    fn implicit_operands_are_not_listed() {
+        assert!(operands(r#"{"operands":[]}"#).is_empty());
+        assert!(serde_json::from_str::<Instruction>(r#"{"addr":1}"#)
+            .unwrap()
+            .operands
+            .is_empty());
+        assert_eq!(
+            operands(r#"{"operands":[{"type":"fp"}]}"#),
+            [Operand::Other {
+                kind: "fp".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn instruction_at() {
+        let aoj = r#"[{"addr":4096,"size":1,"opcode":"push rbp","mnemonic":"push","type":"rpush",
+            "opex":{"operands":[{"type":"reg","value":"rbp","size":8}]}}]"#;
+        let (mut r2p, _) = mock(&[("aoj 1 @ 0x1000", aoj)]);
+        let op = r2p.instruction_at(0x1000).unwrap();
+        assert_eq!(op.mnemonic, "push");
+        assert_eq!(op.operands.len(), 1);
+    }
+
+    #[test]
+    fn instruction_at_without_instruction() {
+        let (mut r2p, _) = mock(&[("aoj 1 @ 0x0", "[]"), ("aoj 1 @ 0x1", "{}")]);
+        for addr in 0..2 {
+            match r2p.instruction_at(addr) {
+                Err(Error::UnexpectedResponse(_)) => {}
+                res => panic!("{:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn disassembly_gets_mnemonics() {
+        let pdj = r#"[{"offset":4096,"opcode":"mov eax, 1"},{"offset":4101,"opcode":"ret","mnemonic":"ret"}]"#;
+        let (mut r2p, _) = mock(&[("pdj 2 @ 0x1000", pdj)]);
+        let ops = r2p.disassemble_at(0x1000, 2).unwrap();
+        assert_eq!(ops[0].addr, 0x1000);
+        assert_eq!(ops[0].mnemonic, "mov");
+        assert_eq!(ops[1].mnemonic, "ret");
+    }
+}
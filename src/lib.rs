@@ -49,6 +49,24 @@
 //!
 //! The crate offers various methods to interact with r2pipe, eg. via process (multi-threadable), http or tcp.
 //! Check the examples/ dir for more complete examples.
+//!
+//! `cmdj` and every helper returning parsed JSON live behind the `json`
+//! feature, which is enabled by default. Disable default features to drop
+//! the serde dependencies when only plain text output is needed.
+//!
+//! The `http` and `ws` features add transports to the r2 web server (`=h`),
+//! `R2Pipe::http` making one request per command and `R2Pipe::ws` keeping a
+//! single WebSocket open. Both are disabled by default.
+//!
+//! With the `log` feature, every command sent to r2 is logged through the
+//! [log](https://docs.rs/log) crate.
+//!
+//! # Thread safety
+//! A pipe carries one command at a time: each method sends a command and
+//! reads its whole reply before returning, hence takes `&mut self`. Every
+//! pipe is `Send`, so it can be moved to another thread or shared behind an
+//! `Arc<Mutex<R2Pipe>>`, but none is `Sync`. To run commands in parallel,
+//! use one pipe, and one r2, per thread, eg. with `R2Pipe::threads`.
 
 #![cfg_attr(doc_cfg, feature(doc_cfg))]
 #![doc(html_root_url = "https://radare.github.io/r2pipe.rs/")]
@@ -57,13 +75,77 @@
 pub mod r2pipe;
 pub mod r2;
 
+pub mod analysis;
+#[cfg(feature = "async")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "async")))]
+pub mod async_pipe;
+pub mod cache;
+pub mod config;
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+pub mod debug;
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+pub mod esil;
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+pub mod instruction;
+pub mod memory;
+pub mod meta;
+pub mod pool;
+pub mod seek;
+pub mod session;
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+pub mod structs;
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+pub mod task;
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+pub mod version;
+
 mod error;
 pub use error::*;
 
+#[cfg(test)]
+mod test_util;
+
 #[doc(hidden)]
 pub type Result<T> = std::result::Result<T, error::Error>;
 
 // Rexport to bring it out one module.
+pub use self::analysis::ExportKind;
+#[cfg(feature = "json")]
+pub use self::analysis::{
+    AnalysisStats, Class, JumpCase, JumpTable, Method, Variable, VariableKind, VariableStorage,
+    Zignature,
+};
+#[cfg(feature = "async")]
+pub use self::async_pipe::AsyncR2Pipe;
+#[cfg(feature = "json")]
+pub use self::debug::{Breakpoint, Debugger};
+#[cfg(feature = "json")]
+pub use self::esil::Esil;
+#[cfg(feature = "json")]
+pub use self::instruction::{Instruction, Operand};
+pub use self::meta::DataKind;
+#[cfg(feature = "json")]
+pub use self::meta::Metadata;
+pub use self::pool::R2Pool;
 pub use self::r2::R2;
 pub use self::r2pipe::R2Pipe;
+#[cfg(feature = "http")]
+pub use self::r2pipe::R2PipeHttpOptions;
 pub use self::r2pipe::R2PipeSpawnOptions;
+pub use self::r2pipe::R2PipeSpawnOptionsBuilder;
+pub use self::r2pipe::R2PipeTransport;
+pub use self::r2pipe::RecoveryPolicy;
+pub use self::r2pipe::TransportKind;
+pub use self::session::Session;
+#[cfg(feature = "json")]
+pub use self::structs::{BinInfo, FunctionInfo, ImportInfo, SectionInfo, StringInfo, SymbolInfo};
+#[cfg(feature = "json")]
+pub use self::task::{TaskHandle, TaskStatus};
+#[cfg(feature = "json")]
+pub use self::version::R2Version;
@@ -0,0 +1,168 @@
+//! Helpers to read and write the memory of the r2 session.
+
+use crate::{Error, R2Pipe, Result};
+
+/// Default number of bytes written by a single `wx` command.
+///
+/// Every byte is sent as two hex digits, so this keeps each command around
+/// 2 KiB, well under the 4 KiB line buffer of the r2 prompt.
+pub const DEFAULT_WRITE_CHUNK: usize = 1024;
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match *pair {
+            [hi, lo] => Some((hex_digit(hi)? << 4) | hex_digit(lo)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+impl R2Pipe {
+    /// Reads `len` bytes at `addr` (`p8 len @ addr`).
+    ///
+    /// `Error::UnexpectedResponse` is returned if r2 printed anything but
+    /// `len` bytes in hex, eg. when reading past the end of the file.
+    pub fn read_bytes(&mut self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let res = self.cmd(&format!("p8 {} @ {:#x}", len, addr))?;
+        let hex = res.trim();
+        match from_hex(hex) {
+            Some(data) if data.len() == len => Ok(data),
+            _ => Err(Error::UnexpectedResponse(hex.to_owned())),
+        }
+    }
+
+    /// Writes `data` at `addr`, split in chunks of `DEFAULT_WRITE_CHUNK`
+    /// bytes. See `R2Pipe::write_bytes_chunked`.
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<()> {
+        self.write_bytes_chunked(addr, data, DEFAULT_WRITE_CHUNK)
+    }
+
+    /// Writes `data` at `addr` issuing one `wx <hex> @ <addr + offset>`
+    /// command for every `chunk_size` bytes.
+    ///
+    /// Each chunk is read back with `p8` once written and
+    /// `Error::WriteFailed` is returned with the address of the first chunk
+    /// which did not make it, so a partial write never goes unnoticed.
+    ///
+    /// `Error::ArgumentMismatch` is returned without writing anything when
+    /// `chunk_size` is 0 or `data` does not fit below the end of the address
+    /// space.
+    pub fn write_bytes_chunked(&mut self, addr: u64, data: &[u8], chunk_size: usize) -> Result<()> {
+        if chunk_size == 0 {
+            return Err(Error::ArgumentMismatch);
+        }
+        let last = (data.len() as u64).saturating_sub(1);
+        if addr.checked_add(last).is_none() {
+            return Err(Error::ArgumentMismatch);
+        }
+
+        for (n, chunk) in data.chunks(chunk_size).enumerate() {
+            let at = addr + (n * chunk_size) as u64;
+            let hex = to_hex(chunk);
+            self.cmd(&format!("wx {} @ {:#x}", hex, at))?;
+
+            let written = self.cmd(&format!("p8 {} @ {:#x}", chunk.len(), at))?;
+            if !written.trim().eq_ignore_ascii_case(&hex) {
+                return Err(Error::WriteFailed(at));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    #[test]
+    fn hex_round_trip() {
+        let data = [0x00, 0x7f, 0x80, 0xff, 0x0a];
+        assert_eq!(to_hex(&data), "007f80ff0a");
+        assert_eq!(from_hex("007f80ff0a").unwrap(), data);
+        assert_eq!(from_hex("007F80FF0A").unwrap(), data);
+        assert_eq!(from_hex("").unwrap(), Vec::<u8>::new());
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("0x"), None);
+    }
+
+    #[test]
+    fn write_split_in_chunks() {
+        let (mut r2p, sent) = mock(&[
+            ("p8 2 @ 0x1000", "0102\n"),
+            ("p8 2 @ 0x1002", "0304\n"),
+            ("p8 1 @ 0x1004", "05\n"),
+        ]);
+        r2p.write_bytes_chunked(0x1000, &[1, 2, 3, 4, 5], 2)
+            .unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                "wx 0102 @ 0x1000",
+                "p8 2 @ 0x1000",
+                "wx 0304 @ 0x1002",
+                "p8 2 @ 0x1002",
+                "wx 05 @ 0x1004",
+                "p8 1 @ 0x1004",
+            ]
+        );
+    }
+
+    #[test]
+    fn write_stops_at_the_first_failed_chunk() {
+        // the second chunk falls in a read-only map
+        let (mut r2p, sent) = mock(&[("p8 2 @ 0x1000", "0102\n"), ("p8 2 @ 0x1002", "ffff\n")]);
+        match r2p.write_bytes_chunked(0x1000, &[1, 2, 3, 4, 5], 2) {
+            Err(Error::WriteFailed(at)) => assert_eq!(at, 0x1002),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(sent.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn write_past_the_address_space() {
+        let (mut r2p, sent) = mock(&[]);
+        assert!(matches!(
+            r2p.write_bytes(u64::MAX - 1, &[1, 2, 3]),
+            Err(Error::ArgumentMismatch)
+        ));
+        assert!(matches!(
+            r2p.write_bytes_chunked(0, &[1], 0),
+            Err(Error::ArgumentMismatch)
+        ));
+        // up to the last address is fine
+        r2p.write_bytes(u64::MAX - 1, &[]).unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+        let (mut r2p, _) = mock(&[("p8 2 @ 0xfffffffffffffffe", "0102\n")]);
+        r2p.write_bytes(u64::MAX - 1, &[1, 2]).unwrap();
+    }
+
+    #[test]
+    fn read_checks_the_reply_length() {
+        let (mut r2p, sent) = mock(&[
+            ("p8 4 @ 0x1000", "7f454c46\n"),
+            // past the end of the file
+            ("p8 4 @ 0x2000", "0102\n"),
+        ]);
+        assert_eq!(r2p.read_bytes(0x1000, 4).unwrap(), b"\x7fELF");
+        match r2p.read_bytes(0x2000, 4) {
+            Err(Error::UnexpectedResponse(ref out)) => assert_eq!(out, "0102"),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(r2p.read_bytes(0x1000, 0).unwrap().is_empty());
+        assert_eq!(*sent.lock().unwrap(), ["p8 4 @ 0x1000", "p8 4 @ 0x2000"]);
+    }
+}
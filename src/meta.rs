@@ -0,0 +1,123 @@
+//! Helpers to define and query r2 metadata, the `C` command family.
+
+#[cfg(feature = "json")]
+use crate::Error;
+use crate::{R2Pipe, Result};
+
+#[cfg(feature = "json")]
+use serde_derive::Deserialize;
+
+/// How the bytes at an address should be interpreted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataKind {
+    /// A string (`Cs size`).
+    String,
+    /// Raw data (`Cd size`).
+    Data,
+    /// `count` consecutive elements of `size` bytes each (`Cd size count`).
+    Array { count: usize },
+    /// A structure described by a `pf` format string (`Cf size format`).
+    Format(String),
+    /// Code: removes any metadata covering the range (`C- size`).
+    Code,
+}
+
+impl DataKind {
+    fn command(&self, size: usize) -> String {
+        match *self {
+            DataKind::String => format!("Cs {}", size),
+            DataKind::Data => format!("Cd {}", size),
+            DataKind::Array { count } => format!("Cd {} {}", size, count),
+            DataKind::Format(ref fmt) => format!("Cf {} {}", size, fmt),
+            DataKind::Code => format!("C- {}", size),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+/// A metadata entry, as listed by `Cj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    #[serde(rename = "offset")]
+    pub addr: u64,
+    /// The `C` subcommand which created the entry, eg. `Cd` or `Cs`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub size: u64,
+    /// Comment, string contents or format, depending on `kind`.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl R2Pipe {
+    /// Defines the `size` bytes at `addr` as `kind`.
+    ///
+    /// For `DataKind::Array` the size is the one of a single element.
+    pub fn define_data(&mut self, addr: u64, kind: DataKind, size: usize) -> Result<()> {
+        self.cmd(&format!("{} @ {:#x}", kind.command(size), addr))?;
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the metadata entries covering `addr` (`Cj. @ addr`), eg. a
+    /// data definition and a comment. Filter them on `kind` to get one of
+    /// them.
+    pub fn metadata_at(&mut self, addr: u64) -> Result<Vec<Metadata>> {
+        let json = match self.cmdj(&format!("Cj. @ {:#x}", addr)) {
+            // r2 may print nothing rather than `[]`
+            Err(Error::EmptyResponse) => return Ok(vec![]),
+            res => res?,
+        };
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    #[test]
+    fn define_data_commands() {
+        let (mut r2p, sent) = mock(&[]);
+        r2p.define_data(0x10, DataKind::String, 6).unwrap();
+        r2p.define_data(0x10, DataKind::Data, 4).unwrap();
+        r2p.define_data(0x10, DataKind::Array { count: 3 }, 8)
+            .unwrap();
+        r2p.define_data(0x10, DataKind::Format("xd foo bar".to_owned()), 8)
+            .unwrap();
+        r2p.define_data(0x10, DataKind::Code, 2).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [
+                "Cs 6 @ 0x10",
+                "Cd 4 @ 0x10",
+                "Cd 8 3 @ 0x10",
+                "Cf 8 xd foo bar @ 0x10",
+                "C- 2 @ 0x10",
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata_at_lists_every_kind() {
+        let cj = r#"[{"offset":4096,"type":"CCu","name":"entry of the table"},
+            {"offset":4096,"type":"Cd","name":"","size":4}]"#;
+        let (mut r2p, _) = mock(&[("Cj. @ 0x1000", cj)]);
+        let entries = r2p.metadata_at(0x1000).unwrap();
+        assert_eq!(entries.len(), 2);
+        let data = entries.iter().find(|m| m.kind == "Cd").unwrap();
+        assert_eq!((data.addr, data.size), (0x1000, 4));
+    }
+
+    #[test]
+    fn metadata_at_without_entry() {
+        let (mut r2p, _) = mock(&[("Cj. @ 0x1000", "[]")]);
+        assert!(r2p.metadata_at(0x1000).unwrap().is_empty());
+        // an empty reply means there is none either
+        assert!(r2p.metadata_at(0x2000).unwrap().is_empty());
+    }
+}
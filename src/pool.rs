@@ -0,0 +1,171 @@
+//! A pool of r2 instances working on the same file, to spread read-mostly
+//! commands over several processes.
+
+use crate::r2pipe::R2PipeSpawnOptions;
+use crate::{Error, R2Pipe, Result};
+
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde_json::Value;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Several r2 spawned on the same file, each command being run by an idle
+/// one.
+///
+/// The pool is `Sync`: share it between threads with an `Arc` and call
+/// `cmd` from each of them. Every r2 has its own state, so commands which
+/// change it, such as seeking or analyzing, only affect the one they ran
+/// on; prefer temporary seeks (`pdf @ addr`) and `cmd_all` to set every r2
+/// up the same way, eg. loading a project.
+///
+/// ```no_run
+/// # use r2pipe::R2Pool;
+/// # use std::sync::Arc;
+/// # use std::thread;
+/// # fn main() -> r2pipe::Result<()> {
+/// let pool = Arc::new(R2Pool::spawn("/bin/ls", 4, None)?);
+/// pool.cmd_all("aa")?;
+/// let workers: Vec<_> = (0..4)
+///     .map(|i| {
+///         let pool = pool.clone();
+///         thread::spawn(move || pool.cmd(&format!("pdf @ entry{}", i)))
+///     })
+///     .collect();
+/// # Ok(())
+/// # }
+/// ```
+pub struct R2Pool {
+    pipes: Vec<Mutex<R2Pipe>>,
+    /// Where to start looking for an idle r2, so they all get used.
+    next: AtomicUsize,
+}
+
+impl R2Pool {
+    /// Spawns `size` r2 on `file`, see `R2Pipe::spawn`. Fails with
+    /// `Error::ArgumentMismatch` when `size` is 0.
+    pub fn spawn<T: AsRef<str>>(
+        file: T,
+        size: usize,
+        opts: Option<R2PipeSpawnOptions>,
+    ) -> Result<R2Pool> {
+        if size == 0 {
+            return Err(Error::ArgumentMismatch);
+        }
+        let pipes = (0..size)
+            .map(|_| R2Pipe::spawn(file.as_ref(), opts.clone()))
+            .collect::<Result<_>>()?;
+        Ok(R2Pool::new(pipes))
+    }
+
+    fn new(pipes: Vec<R2Pipe>) -> R2Pool {
+        R2Pool {
+            pipes: pipes.into_iter().map(Mutex::new).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of r2 in the pool.
+    pub fn size(&self) -> usize {
+        self.pipes.len()
+    }
+
+    /// Takes the first idle r2, going round from the one after the last
+    /// taken, or waits for the next one in turn when they are all busy.
+    fn pipe(&self) -> MutexGuard<'_, R2Pipe> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % self.pipes.len();
+        for i in 0..self.pipes.len() {
+            let pipe = &self.pipes[(start + i) % self.pipes.len()];
+            if let Ok(guard) = pipe.try_lock() {
+                return guard;
+            }
+        }
+        // a panic while running a command leaves the pipe usable
+        self.pipes[start].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs `cmd` on an idle r2, see `R2Pipe::cmd`.
+    pub fn cmd(&self, cmd: &str) -> Result<String> {
+        self.pipe().cmd(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Runs `cmd` on an idle r2, see `R2Pipe::cmdj`.
+    pub fn cmdj(&self, cmd: &str) -> Result<Value> {
+        self.pipe().cmdj(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Runs `cmd` on an idle r2, see `R2Pipe::cmd_typed`.
+    pub fn cmd_typed<T: DeserializeOwned>(&self, cmd: &str) -> Result<T> {
+        self.pipe().cmd_typed(cmd)
+    }
+
+    /// Runs `cmd` on every r2 of the pool, one after the other, and returns
+    /// their outputs. Stops at the first error.
+    pub fn cmd_all(&self, cmd: &str) -> Result<Vec<String>> {
+        self.pipes
+            .iter()
+            .map(|p| p.lock().unwrap_or_else(|e| e.into_inner()).cmd(cmd))
+            .collect()
+    }
+
+    /// Closes every r2 of the pool.
+    pub fn close(self) {
+        for pipe in self.pipes {
+            pipe.into_inner().unwrap_or_else(|e| e.into_inner()).close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock, Sent};
+
+    fn pool(size: usize) -> (R2Pool, Vec<Sent>) {
+        let (pipes, sent) = (0..size).map(|_| mock(&[])).unzip();
+        (R2Pool::new(pipes), sent)
+    }
+
+    #[test]
+    fn commands_go_round_the_pool() {
+        let (pool, sent) = pool(3);
+        for i in 0..6 {
+            pool.cmd(&format!("pd {}", i)).unwrap();
+        }
+        let sent: Vec<_> = sent.iter().map(|s| s.lock().unwrap().clone()).collect();
+        assert_eq!(sent, [["pd 0", "pd 3"], ["pd 1", "pd 4"], ["pd 2", "pd 5"]]);
+    }
+
+    #[test]
+    fn busy_pipes_are_skipped() {
+        let (pool, sent) = pool(3);
+        let busy = pool.pipes[0].lock().unwrap();
+        pool.cmd("pd 0").unwrap();
+        drop(busy);
+        assert!(sent[0].lock().unwrap().is_empty());
+        assert_eq!(*sent[1].lock().unwrap(), ["pd 0"]);
+    }
+
+    #[test]
+    fn cmd_all_runs_on_every_pipe() {
+        let (pool, sent) = pool(3);
+        assert_eq!(pool.cmd_all("e asm.bytes=false").unwrap().len(), 3);
+        for s in &sent {
+            assert_eq!(*s.lock().unwrap(), ["e asm.bytes=false"]);
+        }
+    }
+
+    #[test]
+    fn empty_pool() {
+        assert!(matches!(
+            R2Pool::spawn("/bin/ls", 0, None),
+            Err(Error::ArgumentMismatch)
+        ));
+    }
+}
@@ -12,6 +12,9 @@
 //! barebones implementation of the pipe concept.
 
 use crate::{r2pipe::R2Pipe, Error, Result};
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
 use serde_json::Value;
 
 pub struct R2 {
@@ -43,7 +46,7 @@ impl R2 {
     }
 
     pub fn in_session() -> bool {
-        R2Pipe::in_session().is_some()
+        R2Pipe::has_session()
     }
 
     pub fn from(r2p: R2Pipe) -> R2 {
@@ -69,6 +72,8 @@ impl R2 {
         res
     }
 
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
     pub fn recv_json(&mut self) -> Result<Value> {
         let mut res = self.recv().replace("\n", "");
         if res.is_empty() {
@@ -78,6 +83,18 @@ impl R2 {
         Ok(serde_json::from_str(&res)?)
     }
 
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Deserializes the last output received into `T`, like
+    /// `R2Pipe::cmd_typed`. An empty output is `Error::EmptyResponse`.
+    pub fn recv_typed<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let res = self.recv();
+        if res.trim().is_empty() {
+            return Err(Error::EmptyResponse);
+        }
+        Ok(serde_json::from_str(&res)?)
+    }
+
     pub fn flush(&mut self) {
         self.readin = String::from("");
     }
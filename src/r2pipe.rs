@@ -2,379 +2,3941 @@
 //!
 //! Please check crate level documentation for more details and example.
 
+use crate::cache::{self, Cache};
 use crate::{Error, Result};
 
 #[cfg(feature = "http")]
-use reqwest;
+use flate2::read::{GzDecoder, ZlibDecoder};
+#[cfg(feature = "http")]
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(feature = "ws")]
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
 
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::path::Path;
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::process::Command;
 use std::process::Stdio;
 use std::str;
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "json")]
+use serde::de::{self, DeserializeOwned, Deserializer as _};
+#[cfg(feature = "json")]
+use serde::Deserialize as _;
+#[cfg(feature = "json")]
 use serde_json::Value;
 
-/// File descriptors to the parent r2 process.
-pub struct R2PipeLang {
-    read: BufReader<File>,
-    write: File,
+/// Settings and bookkeeping common to every transport, used by the `R2Pipe`
+/// dispatch.
+#[derive(Default)]
+pub(crate) struct PipeState {
+    cache: Option<Cache>,
+    strict: bool,
+    recovery: Option<RecoveryPolicy>,
+    metrics: Option<Metrics>,
 }
 
-/// Stores descriptors to the spawned r2 process.
-pub struct R2PipeSpawn {
-    read: BufReader<process::ChildStdout>,
-    write: process::ChildStdin,
+/// Time spent in the commands run on the transport, see
+/// `R2Pipe::enable_metrics`.
+#[derive(Default, Clone, Copy)]
+struct Metrics {
+    last: Option<Duration>,
+    total: Duration,
+    count: u64,
 }
 
-/// Stores the socket address of the r2 process.
-pub struct R2PipeTcp {
-    socket_addr: SocketAddr,
+/// How to recover from a lost connection or a crashed r2, see
+/// `R2Pipe::with_recovery`.
+#[derive(Clone, Default)]
+pub struct RecoveryPolicy {
+    /// Attempts made after the first one fails.
+    pub max_retries: u32,
+    /// Delay before the first attempt, doubled before each next one.
+    pub backoff: Duration,
+    /// Commands run on the new session before the failed command is run
+    /// again, eg. `e` settings or `o file`.
+    pub setup: Vec<String>,
+    /// Called with the error that lost the session once it is recovered.
+    pub on_recover: Option<RecoverCallback>,
+}
+
+/// Callback of `RecoveryPolicy::on_recover`.
+pub type RecoverCallback = Arc<dyn Fn(&Error) + Sync + Send>;
+
+/// Whether `e` comes from the connection rather than from the command,
+/// including the stream ending, eg. when r2 crashed.
+fn is_transport_error(e: &Error) -> bool {
+    match *e {
+        Error::Io(_) | Error::ConnectionClosed => true,
+        Error::EmptyResponse | Error::UnexpectedEof { .. } => true,
+        #[cfg(feature = "http")]
+        Error::Reqwest(_) => true,
+        _ => false,
+    }
 }
 
+/// Prefixes r2 starts its error messages with, matched in strict mode.
+///
+/// r2 reports errors on the same channel as the output, so this is a
+/// heuristic: only the first `ERROR_LINES` lines of a reply are examined,
+/// and they must start with one of these exactly, case included.
+pub const ERROR_PREFIXES: &[&str] = &["ERROR:", "ERROR ", "Cannot ", "Invalid ", "Unknown command"];
+
+/// How long `R2Pipe::is_alive` waits for r2 to answer.
+pub const ALIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the HTTP transport waits for each reply, unless set in
+/// `R2PipeHttpOptions::timeout` or with `R2Pipe::set_timeout`. This is the
+/// default of reqwest.
 #[cfg(feature = "http")]
 #[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
-pub struct R2PipeHttp {
-    host: String,
-}
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
 
-/// Stores thread metadata
-/// It stores both a sending and receiving end to the thread, allowing convenient interaction
-/// So we can send commands using R2PipeThread::send() and fetch outputs using R2PipeThread::recv()
-pub struct R2PipeThread {
-    r2recv: mpsc::Receiver<String>,
-    r2send: mpsc::Sender<String>,
-    pub id: u16,
-    pub handle: thread::JoinHandle<Result<()>>,
-}
+/// How long `R2Pipe::cmd_retry` waits before running a command again.
+pub const RETRY_DELAY: Duration = Duration::from_millis(50);
 
-#[derive(Default, Clone)]
-pub struct R2PipeSpawnOptions {
-    pub exepath: String,
-    pub args: Vec<&'static str>,
-}
+/// Echoed between the commands of a batch to tell their outputs apart.
+pub(crate) const BATCH_MARKER: &str = "--r2pipe-batch-8f3a--";
 
-/// Provides abstraction between the three invocation methods.
-pub enum R2Pipe {
-    Pipe(R2PipeSpawn),
-    Lang(R2PipeLang),
-    Tcp(R2PipeTcp),
-    #[cfg(feature = "http")]
-    #[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
-    Http(R2PipeHttp),
+/// Longest line `R2Pipe::cmd_batch` sends at once, well under the 4 KiB
+/// line buffer of the r2 prompt.
+pub const BATCH_LINE_MAX: usize = 2048;
+
+/// Characters r2 gives a meaning to on a command line, which prevent
+/// commands from being joined with `;`.
+const BATCH_UNSAFE: &[char] = &[';', '|', '>', '#', '~', '"', '\'', '`'];
+
+/// Number of leading lines of a reply examined in strict mode.
+pub const ERROR_LINES: usize = 3;
+
+fn is_error(res: &str) -> bool {
+    res.lines()
+        .take(ERROR_LINES)
+        .any(|l| ERROR_PREFIXES.iter().any(|p| l.starts_with(p)))
 }
 
-fn atoi(k: &str) -> i32 {
-    k.parse::<i32>().unwrap_or(-1)
+/// File descriptors (or the named pipe, on Windows) to the parent r2 process.
+pub struct R2PipeLang {
+    read: BufReader<LangIn>,
+    /// `None` when r2 handed a single bidirectional descriptor or named
+    /// pipe, in which case commands are written through `read`.
+    write: Option<File>,
+    /// Whether the reply to a command which timed out is still to be read,
+    /// and skipped, before the next command.
+    stale: bool,
+    state: PipeState,
 }
 
-fn getenv(k: &str) -> i32 {
-    match env::var(k) {
-        Ok(val) => atoi(&val),
-        Err(_) => -1,
-    }
+/// Input of `R2PipeLang`, waiting at most `timeout` for each read.
+struct LangIn {
+    file: File,
+    timeout: Option<Duration>,
 }
 
-fn process_result(res: Vec<u8>) -> Result<String> {
-    let len = res.len();
-    if len == 0 {
-        Err(Error::EmptyResponse)
-    } else {
-        Ok(str::from_utf8(&res[..len - 1])?.to_string())
+impl LangIn {
+    fn new(file: File) -> LangIn {
+        LangIn {
+            file,
+            timeout: None,
+        }
     }
 }
 
-#[macro_export]
-macro_rules! open_pipe {
-	() => {
-            R2Pipe::open(),
-        };
-	($x: expr) => {
-		match $x {
-			Some(path) => R2Pipe::spawn(path, None),
-			None => R2Pipe::open(),
-		}
-	};
-	($x: expr, $y: expr) => {
-		match $x $y {
-			Some(path, opts) => R2Pipe::spawn(path, opts),
-			(None, None) => R2Pipe::open(),
-		}
-	}
+impl Read for LangIn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        if let Some(t) = self.timeout {
+            wait_readable(&self.file, t)?;
+        }
+        self.file.read(buf)
+    }
 }
 
-impl R2Pipe {
-    #[cfg(not(windows))]
-    pub fn open() -> Result<R2Pipe> {
-        use std::os::unix::io::FromRawFd;
-
-        let (f_in, f_out) = R2Pipe::in_session().ok_or(Error::NoSession)?;
-
-        let res = unsafe {
-            // dup file descriptors to avoid from_raw_fd ownership issue
-            let (d_in, d_out) = (libc::dup(f_in), libc::dup(f_out));
-            R2PipeLang {
-                read: BufReader::new(File::from_raw_fd(d_in)),
-                write: File::from_raw_fd(d_out),
+/// Waits up to `timeout` for `fd` to have data, or to be closed.
+#[cfg(unix)]
+fn wait_readable<F: std::os::unix::io::AsRawFd>(fd: &F, timeout: Duration) -> io::Result<()> {
+    let mut pfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    loop {
+        match unsafe { libc::poll(&mut pfd, 1, ms) } {
+            0 => return Err(io::ErrorKind::TimedOut.into()),
+            n if n > 0 => return Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
             }
-        };
-        Ok(R2Pipe::Lang(res))
+        }
     }
+}
 
-    #[cfg(windows)]
-    pub fn open() -> Result<R2Pipe> {
-        unimplemented!()
-    }
+/// Output of the spawned r2 process.
+///
+/// r2's stdout is read directly until a timeout is configured. From then on
+/// a helper thread forwards whatever r2 writes, so that waiting for a reply
+/// can be given up without leaving a blocked read behind.
+enum SpawnOut {
+    Direct(process::ChildStdout),
+    Threaded {
+        rx: mpsc::Receiver<Vec<u8>>,
+        pending: Vec<u8>,
+        timeout: Option<Duration>,
+    },
+    Closed,
+}
 
-    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
+impl SpawnOut {
+    fn timeout(&self) -> Option<Duration> {
         match *self {
-            R2Pipe::Pipe(ref mut x) => x.cmd(cmd.trim()),
-            R2Pipe::Lang(ref mut x) => x.cmd(cmd.trim()),
-            R2Pipe::Tcp(ref mut x) => x.cmd(cmd.trim()),
-            #[cfg(feature = "http")]
-            R2Pipe::Http(ref mut x) => x.cmd(cmd.trim()),
+            SpawnOut::Threaded { timeout, .. } => timeout,
+            _ => None,
         }
     }
 
-    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
-        match *self {
-            R2Pipe::Pipe(ref mut x) => x.cmdj(cmd.trim()),
-            R2Pipe::Lang(ref mut x) => x.cmdj(cmd.trim()),
-            R2Pipe::Tcp(ref mut x) => x.cmdj(cmd.trim()),
-            #[cfg(feature = "http")]
-            R2Pipe::Http(ref mut x) => x.cmdj(cmd.trim()),
+    fn set_timeout(&mut self, t: Option<Duration>) {
+        if let SpawnOut::Threaded {
+            ref mut timeout, ..
+        } = *self
+        {
+            *timeout = t;
+            return;
+        }
+        if let SpawnOut::Direct(mut out) = mem::replace(self, SpawnOut::Closed) {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let mut buf = [0u8; 8192];
+                loop {
+                    match out.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if tx.send(buf[..n].to_vec()).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+            *self = SpawnOut::Threaded {
+                rx,
+                pending: Vec::new(),
+                timeout: t,
+            };
         }
     }
+}
 
-    pub fn close(&mut self) {
+impl Read for SpawnOut {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
-            R2Pipe::Pipe(ref mut x) => x.close(),
-            R2Pipe::Lang(ref mut x) => x.close(),
-            R2Pipe::Tcp(ref mut x) => x.close(),
-            #[cfg(feature = "http")]
-            R2Pipe::Http(ref mut x) => x.close(),
+            SpawnOut::Direct(ref mut out) => out.read(buf),
+            SpawnOut::Threaded {
+                ref rx,
+                ref mut pending,
+                timeout,
+            } => {
+                if pending.is_empty() {
+                    let chunk = match timeout {
+                        Some(t) => match rx.recv_timeout(t) {
+                            Ok(chunk) => chunk,
+                            Err(mpsc::RecvTimeoutError::Timeout) => {
+                                return Err(io::ErrorKind::TimedOut.into())
+                            }
+                            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+                        },
+                        None => match rx.recv() {
+                            Ok(chunk) => chunk,
+                            Err(_) => return Ok(0),
+                        },
+                    };
+                    *pending = chunk;
+                }
+                let n = buf.len().min(pending.len());
+                buf[..n].copy_from_slice(&pending[..n]);
+                pending.drain(..n);
+                Ok(n)
+            }
+            SpawnOut::Closed => Ok(0),
         }
     }
+}
 
-    pub fn in_session() -> Option<(i32, i32)> {
-        let f_in = getenv("R2PIPE_IN");
-        let f_out = getenv("R2PIPE_OUT");
-        if f_in < 0 || f_out < 0 {
-            return None;
-        }
-        Some((f_in, f_out))
+/// Stores descriptors to the spawned r2 process.
+///
+/// Dropping it closes the session and reaps r2, killing it if it does not
+/// exit on its own.
+pub struct R2PipeSpawn {
+    child: process::Child,
+    read: BufReader<SpawnOut>,
+    /// Taken on `close` so r2 sees EOF on its stdin.
+    write: Option<process::ChildStdin>,
+    /// Everything r2 wrote to stderr, when capturing was requested.
+    stderr: Option<Arc<Mutex<Vec<u8>>>>,
+    /// What `R2Pipe::spawn` was given, to start r2 again.
+    origin: Option<Box<(String, R2PipeSpawnOptions)>>,
+    state: PipeState,
+}
+
+/// Stores the connection to an r2 server, kept open across commands.
+pub struct R2PipeSocket<S: Socket> {
+    stream: Option<BufReader<S>>,
+    /// Where `stream` was connected, to reconnect to.
+    addr: S::Addr,
+    timeout: Option<Duration>,
+    /// Whether a reply was read through `stream`, see `read_reply`.
+    answered: bool,
+    /// Whether r2 closed the connection after a reply, so that the next
+    /// command connects again.
+    reopen: bool,
+    state: PipeState,
+}
+
+/// Stores the connection to the r2 process, kept open across commands.
+pub type R2PipeTcp = R2PipeSocket<TcpStream>;
+
+/// Stores the connection to an r2 server listening on a Unix socket.
+#[cfg(unix)]
+#[cfg_attr(doc_cfg, doc(cfg(unix)))]
+pub type R2PipeUnix = R2PipeSocket<UnixStream>;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A stream to an r2 server, `TcpStream` or `UnixStream`.
+pub trait Socket: Read + Write + Sized + sealed::Sealed {
+    /// Where to connect to.
+    type Addr: Send;
+
+    /// Connects to `addr`, updating it with where the connection was made.
+    fn connect(addr: &mut Self::Addr) -> io::Result<Self>;
+
+    /// Sets how long reads wait, `None` waits forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Closes both directions of the connection.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl sealed::Sealed for TcpStream {}
+
+impl Socket for TcpStream {
+    /// The addresses `R2Pipe::tcp` was given, and which of them the last
+    /// connection was made to.
+    type Addr = (Vec<SocketAddr>, SocketAddr);
+
+    fn connect(addr: &mut Self::Addr) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(&addr.0[..])?;
+        addr.1 = stream.peer_addr()?;
+        Ok(stream)
     }
 
-    #[cfg(windows)]
-    pub fn in_windows_session() -> Option<String> {
-        match env::var("R2PIPE_PATH") {
-            Ok(val) => Some(format!("\\\\.\\pipe\\{}", val)),
-            Err(_) => None,
-        }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
     }
 
-    /// Creates a new R2PipeSpawn.
-    pub fn spawn<T: AsRef<str>>(name: T, opts: Option<R2PipeSpawnOptions>) -> Result<R2Pipe> {
-        if name.as_ref() == "" && R2Pipe::in_session().is_some() {
-            return R2Pipe::open();
-        }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
 
-        let exepath = match opts {
-            Some(ref opt) => opt.exepath.clone(),
-            _ => "r2".to_owned(),
-        };
-        let args = match opts {
-            Some(ref opt) => opt.args.clone(),
-            _ => vec![],
-        };
-        let path = Path::new(name.as_ref());
-        let child = Command::new(exepath)
-            .arg("-q0")
-            .args(&args)
-            .arg(path)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
+#[cfg(unix)]
+impl sealed::Sealed for UnixStream {}
 
-        // If stdin/stdout is not available, hard error
-        let sin = child.stdin.unwrap();
-        let mut sout = child.stdout.unwrap();
+#[cfg(unix)]
+impl Socket for UnixStream {
+    type Addr = PathBuf;
 
-        // flush out the initial null byte.
-        let mut w = [0; 1];
-        sout.read_exact(&mut w)?;
+    fn connect(addr: &mut PathBuf) -> io::Result<UnixStream> {
+        UnixStream::connect(addr)
+    }
 
-        let res = R2PipeSpawn {
-            read: BufReader::new(sout),
-            write: sin,
-        };
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
 
-        Ok(R2Pipe::Pipe(res))
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
     }
+}
 
-    /// Creates a new R2PipeTcp
-    pub fn tcp<A: ToSocketAddrs>(addr: A) -> Result<R2Pipe> {
-        // use `connect` to figure out which socket address works
-        let stream = TcpStream::connect(addr)?;
-        let addr = stream.peer_addr()?;
-        Ok(R2Pipe::Tcp(R2PipeTcp { socket_addr: addr }))
+/// How a reply read from an r2 server up to `delim` ended.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ReplyEnd {
+    /// With `delim`, the connection can carry the next command.
+    Terminated,
+    /// With the end of the stream, so the next command connects again.
+    Closed,
+    /// The connection was closed before any reply.
+    Lost,
+}
+
+/// Tells how `res`, read up to `delim` on a connection which already carried
+/// a terminated reply if `answered` is set, ended.
+///
+/// The stock r2 server (`r2 -c '.:9080'`) runs a single command per
+/// connection and closes it once the output is written, without any
+/// terminator: the end of the stream then ends the reply, and a NUL is
+/// appended when `delim` is one. Which is also the case of an empty reply,
+/// unless the connection already carried a terminated one.
+pub(crate) fn end_reply(res: &mut Vec<u8>, delim: u8, answered: bool) -> ReplyEnd {
+    if res.last() == Some(&delim) {
+        return ReplyEnd::Terminated;
     }
+    if res.is_empty() && answered {
+        return ReplyEnd::Lost;
+    }
+    if delim == 0 {
+        res.push(0);
+    }
+    ReplyEnd::Closed
+}
 
-    #[cfg(feature = "http")]
-    #[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
-    /// Creates a new R2PipeHttp
-    pub fn http(host: &str) -> R2Pipe {
-        R2Pipe::Http(R2PipeHttp {
-            host: host.to_string(),
-        })
+#[cfg(feature = "http")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
+pub struct R2PipeHttp {
+    /// Scheme and host requests are sent to, eg. `http://localhost:9090`.
+    base: String,
+    /// `None` until built again when `R2Pipe::http` could not build it.
+    client: Option<reqwest::Client>,
+    opts: R2PipeHttpOptions,
+    /// The timeout `client` was built with.
+    timeout: Option<Duration>,
+    state: PipeState,
+}
+
+/// Settings of the requests made to the r2 web server, see
+/// `R2Pipe::http_with`.
+#[cfg(feature = "http")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
+#[derive(Default, Clone)]
+pub struct R2PipeHttpOptions {
+    /// Connect with `https` instead of `http`.
+    pub https: bool,
+    /// Headers added to every request.
+    pub headers: Vec<(String, String)>,
+    /// User name and password of HTTP basic authentication.
+    pub basic_auth: Option<(String, Option<String>)>,
+    /// Token sent in an `Authorization: Bearer` header.
+    pub bearer_token: Option<String>,
+    /// How long to wait for each reply, `HTTP_TIMEOUT` when `None`. See
+    /// `R2Pipe::set_timeout` to wait forever.
+    pub timeout: Option<Duration>,
+    /// Trust any certificate over `https`, eg. a self-signed one. Anyone on
+    /// the way can then read and alter the session.
+    pub accept_invalid_certs: bool,
+}
+
+/// A way to reach r2 provided by the caller, see `R2Pipe::from_transport`.
+///
+/// Only plain commands go through it: `cmd`, `cmdj` and the helpers built
+/// on them, the cache, strict mode and metrics are handled by `R2Pipe`.
+/// Methods needing more of the transport, such as `cmd_lines` or
+/// `set_timeout`, return `Error::Unsupported`.
+pub trait R2PipeTransport: Send {
+    /// Runs `cmd`, which holds no newline or NUL byte, and returns its whole
+    /// output, without the NUL terminator of the r2pipe protocol.
+    fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>>;
+
+    /// Opens the connection again after a transport error, for
+    /// `R2Pipe::with_recovery`. Returns `Error::Unsupported` by default.
+    fn reconnect(&mut self) -> Result<()> {
+        Err(Error::Unsupported)
     }
 
-    /// Creates new pipe threads
-    /// First two arguments for R2Pipe::threads() are the same as for R2Pipe::spawn() but inside vectors
-    /// Third and last argument is an option to a callback function
-    /// The callback function takes two Arguments: Thread ID and r2pipe output
-    pub fn threads(
-        names: Vec<&'static str>,
-        opts: Vec<Option<R2PipeSpawnOptions>>,
-        callback: Option<Arc<dyn Fn(u16, String) + Sync + Send>>,
-    ) -> Result<Vec<R2PipeThread>> {
-        if names.len() != opts.len() {
-            return Err(Error::ArgumentMismatch);
-        }
+    /// Ends the session, see `R2Pipe::close`.
+    fn close(&mut self) {}
+}
+
+/// Stores a transport provided by the caller.
+pub struct R2PipeCustom {
+    transport: Box<dyn R2PipeTransport>,
+    state: PipeState,
+}
 
-        let mut pipes = Vec::new();
+/// Stores the WebSocket connection to the r2 web server.
+#[cfg(feature = "ws")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "ws")))]
+pub struct R2PipeWs {
+    socket: Box<WebSocket<MaybeTlsStream<TcpStream>>>,
+    state: PipeState,
+}
 
-        for n in 0..names.len() {
-            let (htx, rx) = mpsc::channel();
-            let (tx, hrx) = mpsc::channel();
-            let name = names[n];
-            let opt = opts[n].clone();
-            let cb = callback.clone();
-            let t = thread::spawn(move || -> Result<()> {
-                let mut r2 = R2Pipe::spawn(name, opt)?;
-                loop {
-                    let cmd: String = hrx.recv()?;
-                    if cmd == "q" {
-                        break;
+/// Lines of the output of a command, read as they come, see
+/// `R2Pipe::cmd_lines`.
+///
+/// Lines are yielded without their `\n`. Whatever was not read when this is
+/// dropped is skipped, so the pipe is ready for the next command.
+/// After an error, including a timeout, the rest of the output is left
+/// unread and the pipe should not be used anymore.
+pub struct Lines<'a> {
+    read: &'a mut dyn BufRead,
+    done: bool,
+}
+
+impl<'a> Lines<'a> {
+    fn new(read: &'a mut dyn BufRead) -> Lines<'a> {
+        Lines { read, done: false }
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        if self.done {
+            return None;
+        }
+        let mut line = Vec::new();
+        loop {
+            let buf = match self.read.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            if buf.is_empty() {
+                self.done = true;
+                return Some(Err(Error::ConnectionClosed));
+            }
+            match buf.iter().position(|&b| b == b'\n' || b == 0) {
+                Some(i) => {
+                    let end = buf[i];
+                    line.extend_from_slice(&buf[..i]);
+                    self.read.consume(i + 1);
+                    if end == 0 {
+                        self.done = true;
+                        if line.is_empty() {
+                            return None;
+                        }
                     }
-                    let res = r2.cmdj(&cmd)?.to_string();
-                    htx.send(res.clone())?;
-                    if let Some(cbs) = cb.clone() {
-                        thread::spawn(move || {
-                            cbs(n as u16, res);
-                        });
-                    };
+                    return Some(into_string(line));
                 }
-                Ok(())
-            });
-            pipes.push(R2PipeThread {
-                r2recv: rx,
-                r2send: tx,
-                id: n as u16,
-                handle: t,
-            });
+                None => {
+                    let n = buf.len();
+                    line.extend_from_slice(buf);
+                    self.read.consume(n);
+                }
+            }
         }
-        Ok(pipes)
     }
 }
 
-impl R2PipeThread {
-    pub fn send(&self, cmd: String) -> Result<()> {
-        Ok(self.r2send.send(cmd)?)
+impl<'a> Drop for Lines<'a> {
+    fn drop(&mut self) {
+        while self.next().is_some() {}
     }
+}
 
-    pub fn recv(&self, block: bool) -> Result<String> {
-        if block {
-            Ok(self.r2recv.recv()?)
-        } else {
-            Ok(self.r2recv.try_recv()?)
+/// Outcome of `R2PipeThread::poll`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Poll {
+    /// The worker has not replied yet.
+    Pending,
+    /// The next reply of the worker.
+    Ready(String),
+    /// The worker stopped, after a shutdown, an error or a panic, and every
+    /// reply has been received.
+    Closed,
+}
+
+/// Identifies a command sent to a `R2PipeThread`, to match it with its
+/// reply. Ids count the commands sent to each worker, from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CommandId(pub u64);
+
+/// A reply of a `R2PipeThread`, with the command it answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: CommandId,
+    pub cmd: String,
+    pub output: String,
+}
+
+/// Requests sent to the worker of a `R2PipeThread`.
+enum ThreadMsg {
+    Cmd(CommandId, String),
+    Shutdown,
+}
+
+/// Sending end of the requests of a `R2PipeThread`, bounded or not.
+enum ThreadSender {
+    Unbounded(mpsc::Sender<ThreadMsg>),
+    Bounded(mpsc::SyncSender<ThreadMsg>),
+}
+
+impl ThreadSender {
+    fn send(&self, msg: ThreadMsg) -> std::result::Result<(), mpsc::SendError<ThreadMsg>> {
+        match *self {
+            ThreadSender::Unbounded(ref tx) => tx.send(msg),
+            ThreadSender::Bounded(ref tx) => tx.send(msg),
+        }
+    }
+
+    fn try_send(&self, msg: ThreadMsg) -> std::result::Result<(), mpsc::TrySendError<ThreadMsg>> {
+        match *self {
+            ThreadSender::Unbounded(ref tx) => tx
+                .send(msg)
+                .map_err(|e| mpsc::TrySendError::Disconnected(e.0)),
+            ThreadSender::Bounded(ref tx) => tx.try_send(msg),
         }
     }
 }
 
-impl R2PipeSpawn {
-    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
-        let cmd = cmd.to_owned() + "\n";
-        self.write.write_all(cmd.as_bytes())?;
+/// Stores thread metadata
+/// It stores both a sending and receiving end to the thread, allowing convenient interaction
+/// So we can send commands using R2PipeThread::send() and fetch outputs using R2PipeThread::recv()
+pub struct R2PipeThread {
+    r2recv: mpsc::Receiver<Response>,
+    r2send: ThreadSender,
+    /// Replies set aside by `recv_for` while looking for another one.
+    pending: RefCell<VecDeque<Response>>,
+    next_cmd: Cell<u64>,
+    /// Number of replies taken out of `r2recv`.
+    received: Cell<u64>,
+    r2panic: mpsc::Receiver<String>,
+    pub id: u16,
+    pub handle: thread::JoinHandle<Result<()>>,
+}
 
-        let mut res: Vec<u8> = Vec::new();
-        self.read.read_until(0u8, &mut res)?;
-        process_result(res)
-    }
+#[derive(Default, Clone)]
+pub struct R2PipeSpawnOptions {
+    /// Executable to run, `r2` when empty.
+    pub exepath: String,
+    pub args: Vec<String>,
+    /// Collect r2's stderr instead of letting it through to ours, see
+    /// `R2PipeSpawn::last_stderr`.
+    pub capture_stderr: bool,
+    /// Working directory of r2, the current one when `None`.
+    pub cwd: Option<PathBuf>,
+    /// Variables set in r2's environment, on top of the inherited ones.
+    pub env: Vec<(String, String)>,
+    /// Configuration variables set before the file is loaded, passed as
+    /// `-e key=value` after `args` and right before the file.
+    pub evals: Vec<(String, String)>,
+    /// Do not wait for the NUL byte r2 prints once started, for wrappers
+    /// which do not print it. Anything they print before replying to the
+    /// first command is then read as part of that reply.
+    pub skip_handshake: bool,
+    /// Start r2 without loading any plugin, user settings or scripts
+    /// (`-NN`), for faster and reproducible startups.
+    pub skip_plugins: bool,
+    /// How long to wait for each reply once r2 is started, see
+    /// `R2PipeSpawn::set_timeout`. Not supported by `AsyncR2Pipe`.
+    pub timeout: Option<Duration>,
+}
 
-    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
-        let result = self.cmd(cmd)?;
-        if result.is_empty() {
-            return Err(Error::EmptyResponse);
+impl R2PipeSpawnOptions {
+    /// Starts building options, with `r2` as the executable.
+    ///
+    /// ```no_run
+    /// # use r2pipe::{R2Pipe, R2PipeSpawnOptions};
+    /// # fn main() -> r2pipe::Result<()> {
+    /// let opts = R2PipeSpawnOptions::builder()
+    ///     .arg("-2")
+    ///     .eval("bin.relocs.apply", "true")
+    ///     .env("R2_CURL", "1")
+    ///     .cwd("/tmp")
+    ///     .build();
+    /// let mut r2p = R2Pipe::spawn("/bin/ls", Some(opts))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> R2PipeSpawnOptionsBuilder {
+        R2PipeSpawnOptionsBuilder {
+            opts: R2PipeSpawnOptions {
+                exepath: "r2".to_owned(),
+                ..Default::default()
+            },
         }
-        Ok(serde_json::from_str(&result)?)
     }
 
-    pub fn close(&mut self) {
-        let _ = self.cmd("q!");
+    /// Appends `args` to the arguments passed to r2.
+    pub fn with_args<I, S>(mut self, args: I) -> R2PipeSpawnOptions
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Returns the arguments passed to r2, but for the file.
+    pub(crate) fn r2_args(&self) -> Vec<String> {
+        let mut res = vec!["-q0".to_owned()];
+        if self.skip_plugins {
+            res.push("-NN".to_owned());
+        }
+        res.extend(self.args.iter().cloned());
+        for (k, v) in &self.evals {
+            res.push("-e".to_owned());
+            res.push(format!("{}={}", k, v));
+        }
+        res
     }
 }
 
-impl R2PipeLang {
-    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
-        self.write.write_all(cmd.as_bytes())?;
-        let mut res: Vec<u8> = Vec::new();
-        self.read.read_until(0u8, &mut res)?;
-        process_result(res)
+/// Builds `R2PipeSpawnOptions`, see `R2PipeSpawnOptions::builder`.
+#[derive(Clone)]
+pub struct R2PipeSpawnOptionsBuilder {
+    opts: R2PipeSpawnOptions,
+}
+
+impl R2PipeSpawnOptionsBuilder {
+    /// Sets the r2 executable to run.
+    pub fn exepath<S: Into<String>>(mut self, exepath: S) -> Self {
+        self.opts.exepath = exepath.into();
+        self
     }
 
-    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
-        let res = self.cmd(cmd)?;
+    /// Appends an argument passed to r2.
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.opts.args.push(arg.into());
+        self
+    }
 
-        Ok(serde_json::from_str(&res)?)
+    /// Appends arguments passed to r2.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.opts = self.opts.with_args(args);
+        self
     }
 
-    pub fn close(&mut self) {
-        // self.read.close();
-        // self.write.close();
+    /// Sets a configuration variable before the file is loaded (`-e`).
+    pub fn eval<K: Into<String>, V: Into<String>>(mut self, key: K, val: V) -> Self {
+        self.opts.evals.push((key.into(), val.into()));
+        self
     }
-}
 
-#[cfg(feature = "http")]
-#[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
-impl R2PipeHttp {
-    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
-        let url = format!("http://{}/cmd/{}", self.host, cmd);
-        let res = reqwest::get(&url)?;
-        let bytes = res.bytes().filter_map(|e| e.ok()).collect::<Vec<_>>();
-        Ok(str::from_utf8(bytes.as_slice()).map(|s| s.to_string())?)
+    /// Sets an environment variable of r2.
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, val: V) -> Self {
+        self.opts.env.push((key.into(), val.into()));
+        self
     }
 
-    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
-        let res = self.cmd(cmd)?;
-        Ok(serde_json::from_str(&res)?)
+    /// Sets the working directory of r2.
+    pub fn cwd<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.opts.cwd = Some(dir.into());
+        self
     }
 
-    pub fn close(&mut self) {}
-}
+    /// Collects r2's stderr, see `R2PipeSpawn::last_stderr`.
+    pub fn capture_stderr(mut self, capture: bool) -> Self {
+        self.opts.capture_stderr = capture;
+        self
+    }
 
-impl R2PipeTcp {
-    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
-        let mut stream = TcpStream::connect(self.socket_addr)?;
-        stream.write_all(cmd.as_bytes())?;
-        let mut res: Vec<u8> = Vec::new();
-        stream.read_to_end(&mut res)?;
-        res.push(0);
-        process_result(res)
+    /// Does not wait for r2's first NUL byte, see
+    /// `R2PipeSpawnOptions::skip_handshake`.
+    pub fn skip_handshake(mut self, skip: bool) -> Self {
+        self.opts.skip_handshake = skip;
+        self
     }
 
-    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
-        let res = self.cmd(cmd)?;
-        Ok(serde_json::from_str(&res)?)
+    /// Starts r2 without plugins, see `R2PipeSpawnOptions::skip_plugins`.
+    pub fn skip_plugins(mut self, skip: bool) -> Self {
+        self.opts.skip_plugins = skip;
+        self
     }
 
-    pub fn close(&mut self) {}
+    /// Sets how long to wait for each reply, see `R2PipeSpawn::set_timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.opts.timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> R2PipeSpawnOptions {
+        self.opts
+    }
+
+    /// Spawns r2 on `file` with these options, see `R2Pipe::spawn`.
+    pub fn open<T: AsRef<str>>(self, file: T) -> Result<R2Pipe> {
+        R2Pipe::spawn(file, Some(self.opts))
+    }
+}
+
+/// The kind of transport of a `R2Pipe`, as reported in errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Spawn,
+    Lang,
+    Tcp,
+    Unix,
+    Http,
+    Ws,
+    Custom,
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TransportKind::Spawn => "spawn",
+            TransportKind::Lang => "lang",
+            TransportKind::Tcp => "tcp",
+            TransportKind::Unix => "unix",
+            TransportKind::Http => "http",
+            TransportKind::Ws => "ws",
+            TransportKind::Custom => "custom",
+        })
+    }
+}
+
+/// Provides abstraction between the three invocation methods.
+///
+/// It is `Send` but not `Sync`, see the crate level documentation.
+pub enum R2Pipe {
+    Pipe(R2PipeSpawn),
+    Lang(R2PipeLang),
+    Tcp(R2PipeTcp),
+    #[cfg(unix)]
+    #[cfg_attr(doc_cfg, doc(cfg(unix)))]
+    Unix(R2PipeUnix),
+    #[cfg(feature = "http")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
+    Http(R2PipeHttp),
+    #[cfg(feature = "ws")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "ws")))]
+    Ws(R2PipeWs),
+    Custom(R2PipeCustom),
+}
+
+fn atoi(k: &str) -> i32 {
+    k.parse::<i32>().unwrap_or(-1)
+}
+
+fn getenv(k: &str) -> i32 {
+    match env::var(k) {
+        Ok(val) => atoi(&val),
+        Err(_) => -1,
+    }
+}
+
+fn panic_message(e: Box<dyn Any + Send>) -> String {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "callback panicked".to_owned()
+    }
+}
+
+/// Escapes everything but the unreserved characters of RFC 3986, so commands
+/// like `pdf @ sym.main` or `/ foo/bar` fit in a single path segment.
+#[cfg(feature = "http")]
+fn percent_encode(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                res.push(b as char)
+            }
+            _ => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+#[cfg(feature = "http")]
+fn http_client(timeout: Option<Duration>, opts: &R2PipeHttpOptions) -> Result<reqwest::Client> {
+    // replies are decoded by `R2PipeHttp::cmd_bytes`, deflate too
+    Ok(reqwest::Client::builder()
+        .gzip(false)
+        .timeout(timeout)
+        .danger_accept_invalid_certs(opts.accept_invalid_certs)
+        .build()?)
+}
+
+/// Parses the JSON output of `cmd`, which must not be empty.
+///
+/// This works on bytes so that outputs need not be turned into a `String`
+/// first, serde checks the strings it finds are UTF-8 anyway.
+#[cfg(feature = "json")]
+pub(crate) fn parse_json<T: DeserializeOwned>(cmd: &str, res: &[u8]) -> Result<T> {
+    if res.is_empty() {
+        return Err(Error::EmptyResponse);
+    }
+    serde_json::from_slice(res).map_err(|source| Error::JsonParse {
+        cmd: cmd.to_owned(),
+        raw: String::from_utf8_lossy(res).into_owned(),
+        source,
+    })
+}
+
+/// Passes each element of the JSON array in `res`, the output of `cmd`, to
+/// `f` as it is parsed, or the whole output if it is an object, see
+/// `R2Pipe::cmdj_for_each`.
+#[cfg(feature = "json")]
+fn parse_json_each<F>(cmd: &str, res: &[u8], f: F) -> Result<()>
+where
+    F: FnMut(Value) -> Result<()>,
+{
+    if res.is_empty() {
+        return Err(Error::EmptyResponse);
+    }
+    let mut visitor = EachVisitor { f, err: None };
+    let mut de = serde_json::Deserializer::from_slice(res);
+    let parsed = (&mut de)
+        .deserialize_any(&mut visitor)
+        .and_then(|_| de.end());
+    match (visitor.err, parsed) {
+        // the parse was aborted to stop at the failing callback
+        (Some(e), _) => Err(e),
+        (None, Err(source)) => Err(Error::JsonParse {
+            cmd: cmd.to_owned(),
+            raw: String::from_utf8_lossy(res).into_owned(),
+            source,
+        }),
+        (None, Ok(())) => Ok(()),
+    }
+}
+
+#[cfg(feature = "json")]
+struct EachVisitor<F> {
+    f: F,
+    err: Option<Error>,
+}
+
+#[cfg(feature = "json")]
+impl<'de, F> de::Visitor<'de> for &mut EachVisitor<F>
+where
+    F: FnMut(Value) -> Result<()>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON array or object")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        while let Some(elem) = seq.next_element::<Value>()? {
+            if let Err(e) = (self.f)(elem) {
+                self.err = Some(e);
+                return Err(de::Error::custom("stopped by the callback"));
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> std::result::Result<(), A::Error> {
+        let obj = Value::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        if let Err(e) = (self.f)(obj) {
+            self.err = Some(e);
+        }
+        Ok(())
+    }
+}
+
+/// Returns `res` up to the end of the object or array it starts with, when
+/// something follows it, as older r2 builds sometimes append a stray byte.
+///
+/// Brackets are counted outside of strings only, so that nothing but the
+/// tail past the first complete value is ever dropped. `None` is returned
+/// for anything not starting with `{` or `[`, unbalanced or without a tail.
+#[cfg(feature = "json")]
+fn trim_json_tail(res: &str) -> Option<&str> {
+    let start = res.len() - res.trim_start().len();
+    if !res[start..].starts_with(['{', '[']) {
+        return None;
+    }
+    let mut depth = 0usize;
+    let mut in_str = false;
+    let mut escaped = false;
+    for (i, c) in res.char_indices().skip_while(|&(i, _)| i < start) {
+        if in_str {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_str = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_str = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    let end = i + 1;
+                    return if res[end..].trim().is_empty() {
+                        None
+                    } else {
+                        Some(&res[..end])
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Rejects commands r2 would read as several, as only the first reply
+/// would be read. Chain commands with `;` instead.
+pub(crate) fn check_cmd(cmd: &str) -> Result<()> {
+    if cmd.contains(['\n', '\0']) {
+        return Err(Error::InvalidCommand(cmd.to_owned()));
+    }
+    Ok(())
+}
+
+/// Why r2 failed to start when its stderr is not captured.
+pub(crate) const NO_HANDSHAKE: &str = "stdout closed before the handshake";
+
+/// Fails with `Error::UnsafeCommand` when `s` contains `BATCH_UNSAFE`
+/// characters, a temporary seek or, for an argument, whitespace.
+pub(crate) fn check_plain(s: &str, arg: bool) -> Result<()> {
+    if s.contains(BATCH_UNSAFE)
+        || s.contains(['@', '\n', '\0'])
+        || (arg && (s.is_empty() || s.contains(char::is_whitespace)))
+    {
+        return Err(Error::UnsafeCommand(s.to_owned()));
+    }
+    Ok(())
+}
+
+/// Strips the `delim` terminator off a reply, which is `Error::EmptyResponse`
+/// when nothing at all was read and `Error::UnexpectedEof` when the stream
+/// ended before the terminator.
+pub(crate) fn process_result(cmd: &str, mut res: Vec<u8>, delim: u8) -> Result<Vec<u8>> {
+    match res.last() {
+        Some(&b) if b == delim => {
+            res.pop();
+            Ok(res)
+        }
+        Some(_) => Err(Error::UnexpectedEof {
+            cmd: cmd.trim_end().to_owned(),
+            partial: String::from_utf8_lossy(&res).into_owned(),
+        }),
+        None => Err(Error::EmptyResponse),
+    }
+}
+
+/// Turns the error of spawning `program` into `Error::ExecutableNotFound`
+/// when it does not exist.
+pub(crate) fn spawn_error(program: &OsStr, e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        Error::ExecutableNotFound(program.to_string_lossy().into_owned())
+    } else {
+        e.into()
+    }
+}
+
+pub(crate) fn into_string(res: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(res).map_err(|e| e.utf8_error())?)
+}
+
+/// Opens a pipe to the r2 session running this program, or spawns r2 on
+/// the given file.
+///
+/// `open_pipe!()` is `R2Pipe::open()`. With an `Option` of a path, `r2` is
+/// spawned on it when there is one, with spawn options as well when they
+/// are given as a second `Option`, and `R2Pipe::open()` is used otherwise.
+///
+/// ```no_run
+/// # #[macro_use] extern crate r2pipe;
+/// use r2pipe::{R2Pipe, R2PipeSpawnOptions};
+/// # fn main() -> r2pipe::Result<()> {
+/// let _in_session = open_pipe!()?;
+/// let _spawned = open_pipe!(Some("/bin/ls"))?;
+/// let path: Option<&str> = None;
+/// let _in_session = open_pipe!(path)?;
+/// let opts = R2PipeSpawnOptions::builder().arg("-n").build();
+/// let _spawned = open_pipe!(Some("/bin/ls"), Some(opts))?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! open_pipe {
+    () => {
+        $crate::R2Pipe::open()
+    };
+    ($x: expr) => {
+        match $x {
+            Some(path) => $crate::R2Pipe::spawn(path, None),
+            None => $crate::R2Pipe::open(),
+        }
+    };
+    ($x: expr, $y: expr) => {
+        match ($x, $y) {
+            (Some(path), opts) => $crate::R2Pipe::spawn(path, opts),
+            (None, _) => $crate::R2Pipe::open(),
+        }
+    };
+}
+
+impl R2Pipe {
+    /// Connects to the r2 or rizin session running this program, through the
+    /// file descriptors passed in `R2PIPE_IN` and `R2PIPE_OUT`, see
+    /// `in_session`.
+    #[cfg(not(windows))]
+    pub fn open() -> Result<R2Pipe> {
+        let (f_in, f_out) = R2Pipe::in_session().ok_or(Error::NoSession)?;
+        Ok(R2Pipe::open_fds(f_in, f_out))
+    }
+
+    /// Connects through duplicates of `f_in` and `f_out`, which r2 reads
+    /// the commands from and writes the replies to, see `open`.
+    #[cfg(not(windows))]
+    fn open_fds(f_in: i32, f_out: i32) -> R2Pipe {
+        use std::os::unix::io::FromRawFd;
+
+        let res = unsafe {
+            // dup file descriptors to avoid from_raw_fd ownership issue
+            if f_in == f_out {
+                // a single socket for both directions, keep one handle so
+                // reads and writes never race on two descriptors
+                R2PipeLang {
+                    read: BufReader::new(LangIn::new(File::from_raw_fd(libc::dup(f_in)))),
+                    write: None,
+                    stale: false,
+                    state: PipeState::default(),
+                }
+            } else {
+                let (d_in, d_out) = (libc::dup(f_in), libc::dup(f_out));
+                R2PipeLang {
+                    read: BufReader::new(LangIn::new(File::from_raw_fd(d_in))),
+                    write: Some(File::from_raw_fd(d_out)),
+                    stale: false,
+                    state: PipeState::default(),
+                }
+            }
+        };
+        R2Pipe::Lang(res)
+    }
+
+    /// Connects to the r2 or rizin session running this program, through the
+    /// named pipe given in `R2PIPE_PATH`, see `in_windows_session`.
+    #[cfg(windows)]
+    pub fn open() -> Result<R2Pipe> {
+        use std::fs::OpenOptions;
+
+        let path = R2Pipe::in_windows_session().ok_or(Error::NoSession)?;
+        let pipe = OpenOptions::new().read(true).write(true).open(path)?;
+
+        // a named pipe is bidirectional, commands are written through `read`
+        Ok(R2Pipe::Lang(R2PipeLang {
+            read: BufReader::new(LangIn::new(pipe)),
+            write: None,
+            stale: false,
+            state: PipeState::default(),
+        }))
+    }
+
+    /// Returns the kind of transport of the pipe.
+    pub fn transport_kind(&self) -> TransportKind {
+        match *self {
+            R2Pipe::Pipe(_) => TransportKind::Spawn,
+            R2Pipe::Lang(_) => TransportKind::Lang,
+            R2Pipe::Tcp(_) => TransportKind::Tcp,
+            #[cfg(unix)]
+            R2Pipe::Unix(_) => TransportKind::Unix,
+            #[cfg(feature = "http")]
+            R2Pipe::Http(_) => TransportKind::Http,
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(_) => TransportKind::Ws,
+            R2Pipe::Custom(_) => TransportKind::Custom,
+        }
+    }
+
+    /// Builds the `Error::CommandFailed` of `cmd`, which printed `output`.
+    fn command_failed(&self, cmd: &str, output: String) -> Error {
+        Error::CommandFailed {
+            cmd: cmd.to_owned(),
+            transport: self.transport_kind(),
+            stderr: self.last_stderr(),
+            output,
+        }
+    }
+
+    fn state(&mut self) -> &mut PipeState {
+        match *self {
+            R2Pipe::Pipe(ref mut x) => &mut x.state,
+            R2Pipe::Lang(ref mut x) => &mut x.state,
+            R2Pipe::Tcp(ref mut x) => &mut x.state,
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => &mut x.state,
+            #[cfg(feature = "http")]
+            R2Pipe::Http(ref mut x) => &mut x.state,
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(ref mut x) => &mut x.state,
+            R2Pipe::Custom(ref mut x) => &mut x.state,
+        }
+    }
+
+    fn state_ref(&self) -> &PipeState {
+        match *self {
+            R2Pipe::Pipe(ref x) => &x.state,
+            R2Pipe::Lang(ref x) => &x.state,
+            R2Pipe::Tcp(ref x) => &x.state,
+            #[cfg(unix)]
+            R2Pipe::Unix(ref x) => &x.state,
+            #[cfg(feature = "http")]
+            R2Pipe::Http(ref x) => &x.state,
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(ref x) => &x.state,
+            R2Pipe::Custom(ref x) => &x.state,
+        }
+    }
+
+    /// Runs `cmd` on the transport, timing it when metrics are enabled and
+    /// logging it when the `log` feature is enabled: its output length at
+    /// debug level, the output itself at trace level.
+    fn transport_cmd(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        check_cmd(cmd)?;
+        let res = if self.state().metrics.is_some() {
+            let start = Instant::now();
+            let res = self.transport_cmd_reconnect(cmd);
+            let elapsed = start.elapsed();
+            if let Some(ref mut m) = self.state().metrics {
+                m.last = Some(elapsed);
+                m.total += elapsed;
+                m.count += 1;
+            }
+            res
+        } else {
+            self.transport_cmd_reconnect(cmd)
+        };
+        #[cfg(feature = "log")]
+        match res {
+            Ok(ref out) => {
+                log::debug!("r2 command {:?}: {} bytes", cmd, out.len());
+                log::trace!("r2 command {:?}: {:?}", cmd, String::from_utf8_lossy(out));
+            }
+            Err(ref e) => log::debug!("r2 command {:?} failed: {}", cmd, e),
+        }
+        res
+    }
+
+    /// Runs `cmd` on the transport, recovering the session and running it
+    /// once more if it was lost and `with_recovery` was used. A spawned r2
+    /// killed because `cmd` timed out is spawned again, but `cmd` is not
+    /// run once more.
+    fn transport_cmd_reconnect(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        let policy = match self.state().recovery {
+            Some(ref policy) => policy.clone(),
+            None => return self.transport_cmd_once(cmd),
+        };
+        match self.transport_cmd_once(cmd) {
+            Err(err) if is_transport_error(&err) => match self.recover_after(&policy, &err) {
+                Ok(()) => self.transport_cmd_once(cmd),
+                Err(Error::Unsupported) => Err(err),
+                Err(e) => Err(e),
+            },
+            Err(err @ Error::CommandTimeout { .. }) => {
+                // the timeout is what gets reported, a failed respawn shows
+                // on the next command
+                let _ = self.recover_after(&policy, &err);
+                Err(err)
+            }
+            res => res,
+        }
+    }
+
+    /// Recovers the session lost because of `err`, following `policy`.
+    fn recover_after(&mut self, policy: &RecoveryPolicy, err: &Error) -> Result<()> {
+        let mut delay = policy.backoff;
+        let mut attempt = 0;
+        loop {
+            thread::sleep(delay);
+            match self.recover(&policy.setup) {
+                Ok(()) => {
+                    if let Some(ref f) = policy.on_recover {
+                        f(err);
+                    }
+                    return Ok(());
+                }
+                Err(Error::Unsupported) => return Err(Error::Unsupported),
+                Err(e) if attempt >= policy.max_retries => return Err(e),
+                Err(_) => {}
+            }
+            attempt += 1;
+            delay = delay.checked_mul(2).unwrap_or(delay);
+        }
+    }
+
+    /// Reconnects or respawns, then runs the `setup` commands. The output of
+    /// the previous session is no longer valid, so the cache is cleared.
+    fn recover(&mut self, setup: &[String]) -> Result<()> {
+        self.reconnect()?;
+        self.clear_cache();
+        for cmd in setup {
+            self.transport_cmd_once(cmd.trim())?;
+        }
+        Ok(())
+    }
+
+    /// Opens the connection again, for the transports that have one, or
+    /// starts r2 again.
+    fn reconnect(&mut self) -> Result<()> {
+        match *self {
+            R2Pipe::Pipe(ref mut x) => x.respawn(),
+            R2Pipe::Tcp(ref mut x) => x.reconnect(),
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => x.reconnect(),
+            // every request is made anew
+            #[cfg(feature = "http")]
+            R2Pipe::Http(_) => Ok(()),
+            R2Pipe::Custom(ref mut x) => x.transport.reconnect(),
+            _ => Err(Error::Unsupported),
+        }
+    }
+
+    fn transport_cmd_once(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        match *self {
+            R2Pipe::Pipe(ref mut x) => x.cmd_bytes(cmd),
+            R2Pipe::Lang(ref mut x) => x.cmd_bytes(cmd),
+            R2Pipe::Tcp(ref mut x) => x.cmd_bytes(cmd),
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => x.cmd_bytes(cmd),
+            #[cfg(feature = "http")]
+            R2Pipe::Http(ref mut x) => x.cmd_bytes(cmd),
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(ref mut x) => x.cmd_bytes(cmd),
+            R2Pipe::Custom(ref mut x) => x.transport.cmd_bytes(cmd),
+        }
+    }
+
+    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
+        let cmd = cmd.trim();
+        if let Some(ref mut c) = self.state().cache {
+            if !cache::is_cacheable(cmd) {
+                c.clear();
+            } else if let Some(res) = c.get(cmd) {
+                return Ok(res);
+            }
+        }
+
+        let res = into_string(self.transport_cmd(cmd)?)?;
+        if self.state().strict && is_error(&res) {
+            return Err(self.command_failed(cmd, res));
+        }
+        if let Some(ref mut c) = self.state().cache {
+            if cache::is_cacheable(cmd) {
+                c.insert(cmd, &res);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Runs `cmd` like `cmd`, up to `attempts` times as long as it fails with
+    /// `Error::EmptyResponse`, waiting `RETRY_DELAY` in between. Any other
+    /// error is returned at once.
+    ///
+    /// The command is run again as a whole, so calling this with a command
+    /// which seeks, writes or otherwise changes the session means accepting
+    /// that it may take effect more than once; `cmd` never retries.
+    ///
+    /// This only helps over HTTP and TCP: a spawned r2 leaves its replies
+    /// empty when its stdout was closed, eg. because it crashed, which
+    /// `with_recovery` handles by spawning it again.
+    pub fn cmd_retry(&mut self, cmd: &str, attempts: u32) -> Result<String> {
+        let mut attempt = 1;
+        loop {
+            match self.cmd(cmd) {
+                Err(Error::EmptyResponse) if attempt < attempts => {}
+                res => return res,
+            }
+            attempt += 1;
+            thread::sleep(RETRY_DELAY);
+        }
+    }
+
+    /// Runs `cmd` and returns its output as is, for commands printing raw
+    /// bytes that are not valid UTF-8. The output is never cached.
+    pub fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        let cmd = cmd.trim();
+        if !cache::is_cacheable(cmd) {
+            self.clear_cache();
+        }
+        self.transport_cmd(cmd)
+    }
+
+    /// Runs `cmd` and returns its output line by line, read from the pipe as
+    /// they are iterated over rather than all at once.
+    ///
+    /// This saves memory on huge outputs of line oriented commands, such as
+    /// `afl` or `izz`; JSON commands print everything on a single line. The
+    /// output is never cached. The HTTP and WebSocket transports return
+    /// `Error::Unsupported`.
+    pub fn cmd_lines(&mut self, cmd: &str) -> Result<Lines<'_>> {
+        let cmd = cmd.trim();
+        check_cmd(cmd)?;
+        if !cache::is_cacheable(cmd) {
+            self.clear_cache();
+        }
+        match *self {
+            R2Pipe::Pipe(ref mut x) => x.cmd_lines(cmd),
+            R2Pipe::Lang(ref mut x) => x.cmd_lines(cmd),
+            R2Pipe::Tcp(ref mut x) => x.cmd_lines(cmd),
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => x.cmd_lines(cmd),
+            #[cfg(feature = "http")]
+            R2Pipe::Http(_) => Err(Error::Unsupported),
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(_) => Err(Error::Unsupported),
+            R2Pipe::Custom(_) => Err(Error::Unsupported),
+        }
+    }
+
+    /// Runs `cmd` and returns its output up to `delim` instead of the NUL
+    /// terminator, for r2 setups that end replies differently.
+    ///
+    /// Whatever r2 prints after `delim`, a NUL terminator included, is left
+    /// unread and taken as the beginning of the next reply. The output is
+    /// never cached. The HTTP and WebSocket transports return
+    /// `Error::Unsupported`.
+    pub fn cmd_until(&mut self, cmd: &str, delim: u8) -> Result<String> {
+        let cmd = cmd.trim();
+        check_cmd(cmd)?;
+        if !cache::is_cacheable(cmd) {
+            self.clear_cache();
+        }
+        match *self {
+            R2Pipe::Pipe(ref mut x) => x.cmd_until(cmd, delim),
+            R2Pipe::Lang(ref mut x) => x.cmd_until(cmd, delim),
+            R2Pipe::Tcp(ref mut x) => x.cmd_until(cmd, delim),
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => x.cmd_until(cmd, delim),
+            #[cfg(feature = "http")]
+            R2Pipe::Http(_) => Err(Error::Unsupported),
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(_) => Err(Error::Unsupported),
+            R2Pipe::Custom(_) => Err(Error::Unsupported),
+        }
+    }
+
+    /// Runs `cmd` at `addr`, through a temporary seek (`cmd @ addr`).
+    ///
+    /// `cmd` must be a single command, without `;`, `|`, `@` or any other
+    /// character r2 would interpret, see `cmd_fmt`. Use `cmd` to run such
+    /// command lines on purpose.
+    pub fn cmd_at(&mut self, cmd: &str, addr: u64) -> Result<String> {
+        check_plain(cmd, false)?;
+        self.cmd(&format!("{} @ {:#x}", cmd.trim(), addr))
+    }
+
+    /// Runs `cmd` with `args` appended, separated by spaces.
+    ///
+    /// Neither `cmd` nor the arguments may contain characters r2 interprets
+    /// instead of passing them to the command: `;`, `|`, `>`, `#`, `~`,
+    /// `@`, quotes, backticks or newlines. The arguments may contain no
+    /// whitespace either, so each stays a single argument. Any of these
+    /// fail with `Error::UnsafeCommand` before anything is sent.
+    ///
+    /// ```no_run
+    /// # use r2pipe::R2Pipe;
+    /// # fn main() -> r2pipe::Result<()> {
+    /// let mut r2p = R2Pipe::spawn("/bin/ls", None)?;
+    /// let name = "sym.main";
+    /// let disasm = r2p.cmd_fmt("pd", &["10", name])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cmd_fmt<A: AsRef<str>>(&mut self, cmd: &str, args: &[A]) -> Result<String> {
+        check_plain(cmd, false)?;
+        let mut line = cmd.trim().to_owned();
+        for arg in args {
+            check_plain(arg.as_ref(), true)?;
+            line.push(' ');
+            line.push_str(arg.as_ref());
+        }
+        self.cmd(&line)
+    }
+
+    /// Runs every command of `cmds` and returns their outputs, in order.
+    ///
+    /// The commands are sent as `;`-separated lines of at most
+    /// `BATCH_LINE_MAX` bytes, with a marker echoed between them to split
+    /// the output back, which saves a round trip per command. When a
+    /// command contains characters that would change how the line is parsed
+    /// (`;`, `|`, `>`, `#`, `~`, quotes or backticks), they are run one
+    /// after the other through `cmd` instead. Outputs are never cached.
+    pub fn cmd_batch(&mut self, cmds: &[&str]) -> Result<Vec<String>> {
+        let cmds: Vec<&str> = cmds.iter().map(|c| c.trim()).collect();
+        if cmds.is_empty() {
+            return Ok(Vec::new());
+        }
+        if cmds.iter().any(|c| c.contains(BATCH_UNSAFE)) {
+            return cmds.iter().map(|c| self.cmd(c)).collect();
+        }
+        if cmds.iter().any(|c| !cache::is_cacheable(c)) {
+            self.clear_cache();
+        }
+
+        let sep_len = format!(";?e {};", BATCH_MARKER).len();
+        let mut outs = Vec::with_capacity(cmds.len());
+        let mut start = 0;
+        while start < cmds.len() {
+            // a command longer than the limit is sent on its own
+            let mut end = start + 1;
+            let mut len = cmds[start].len();
+            while end < cmds.len() && len + sep_len + cmds[end].len() <= BATCH_LINE_MAX {
+                len += sep_len + cmds[end].len();
+                end += 1;
+            }
+            outs.extend(self.run_batch(&cmds[start..end])?);
+            start = end;
+        }
+        Ok(outs)
+    }
+
+    /// Runs `cmds` as a single line, see `cmd_batch`.
+    fn run_batch(&mut self, cmds: &[&str]) -> Result<Vec<String>> {
+        let sep = format!(";?e {};", BATCH_MARKER);
+        let res = into_string(self.transport_cmd(&cmds.join(&sep))?)?;
+        let mut outs: Vec<String> = Vec::with_capacity(cmds.len());
+        let mut rest = res.as_str();
+        while outs.len() + 1 < cmds.len() {
+            let end = rest
+                .find(BATCH_MARKER)
+                .ok_or_else(|| Error::UnexpectedResponse(res.clone()))?;
+            outs.push(rest[..end].to_owned());
+            rest = &rest[end + BATCH_MARKER.len()..];
+            rest = rest.strip_prefix('\n').unwrap_or(rest);
+        }
+        outs.push(rest.to_owned());
+        if self.state().strict {
+            if let Some(i) = outs.iter().position(|o| is_error(o)) {
+                let out = outs.swap_remove(i);
+                return Err(self.command_failed(cmds[i], out));
+            }
+        }
+        Ok(outs)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
+        self.cmd_typed(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Runs `cmd` and deserializes its JSON output into `T`.
+    ///
+    /// ```no_run
+    /// # use r2pipe::R2Pipe;
+    /// # use serde_derive::Deserialize;
+    /// #[derive(Deserialize)]
+    /// struct Function {
+    ///     name: String,
+    ///     offset: u64,
+    /// }
+    ///
+    /// # fn main() -> r2pipe::Result<()> {
+    /// let mut r2p = R2Pipe::spawn("/bin/ls", None)?;
+    /// r2p.cmd("aa")?;
+    /// let fcns: Vec<Function> = r2p.cmd_typed("aflj")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cmd_typed<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<T> {
+        let cmd = cmd.trim();
+        if self.state().cache.is_some() || self.state().strict {
+            let res = self.cmd(cmd)?;
+            return parse_json(cmd, res.as_bytes());
+        }
+        // nothing to look at but the JSON, which is parsed straight from
+        // the bytes received
+        let res = self.cmd_bytes(cmd)?;
+        parse_json(cmd, &res)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Runs `cmd` and calls `f` on each element of the JSON array it
+    /// outputs, as soon as it is parsed, so the whole array is never held in
+    /// memory as a `Value`. An object is passed to `f` once.
+    ///
+    /// Stops at the first error returned by `f` and returns it. The output
+    /// itself is still read at once.
+    pub fn cmdj_for_each<F>(&mut self, cmd: &str, f: F) -> Result<()>
+    where
+        F: FnMut(Value) -> Result<()>,
+    {
+        let cmd = cmd.trim();
+        if self.state().cache.is_some() || self.state().strict {
+            let res = self.cmd(cmd)?;
+            return parse_json_each(cmd, res.as_bytes(), f);
+        }
+        let res = self.cmd_bytes(cmd)?;
+        parse_json_each(cmd, &res, f)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Like `cmdj`, but if the output fails to parse because of garbage
+    /// after the JSON value, parses it again without that garbage.
+    ///
+    /// Only what follows the first complete object or array is dropped, any
+    /// other malformed output fails like for `cmdj`.
+    pub fn cmdj_lenient(&mut self, cmd: &str) -> Result<Value> {
+        let res = self.cmd(cmd)?;
+        match parse_json(cmd, res.as_bytes()) {
+            Err(e) => match trim_json_tail(&res) {
+                Some(trimmed) => parse_json(cmd, trimmed.as_bytes()).map_err(|_| e),
+                None => Err(e),
+            },
+            ok => ok,
+        }
+    }
+
+    /// Sends `cmd` followed by a raw binary `payload` and returns the reply.
+    ///
+    /// The bytes written are exactly `cmd`, a `\n` and `payload`, with no
+    /// length prefix, encoding or trailing terminator; the reply is read up
+    /// to the NUL byte like for `cmd`. This is meant for IO plugins reading
+    /// their input straight off the pipe. The HTTP transport can not carry
+    /// such payloads and returns `Error::Unsupported`.
+    pub fn cmd_with_binary(&mut self, cmd: &str, payload: &[u8]) -> Result<String> {
+        check_cmd(cmd.trim())?;
+        self.clear_cache();
+        match *self {
+            R2Pipe::Pipe(ref mut x) => x.cmd_with_binary(cmd.trim(), payload),
+            R2Pipe::Lang(ref mut x) => x.cmd_with_binary(cmd.trim(), payload),
+            R2Pipe::Tcp(ref mut x) => x.cmd_with_binary(cmd.trim(), payload),
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => x.cmd_with_binary(cmd.trim(), payload),
+            #[cfg(feature = "http")]
+            R2Pipe::Http(_) => Err(Error::Unsupported),
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(_) => Err(Error::Unsupported),
+            R2Pipe::Custom(_) => Err(Error::Unsupported),
+        }
+    }
+
+    /// Sets how long to wait for the reply to each command. Once elapsed,
+    /// `cmd` returns `Error::Timeout`; `None` waits forever, the default but
+    /// for HTTP, see `HTTP_TIMEOUT`.
+    ///
+    /// A spawned r2 is killed when a command times out, as its late reply
+    /// would be mistaken for the one of the next command, and
+    /// `Error::CommandTimeout` is returned instead; with `with_recovery` it
+    /// is spawned again. The TCP and Unix socket connections are dropped.
+    /// Within an r2 session, see `open`, the late reply is skipped before
+    /// the next command, which fails with `Error::Timeout` while r2 is still
+    /// busy. Only the spawn, TCP, Unix socket, HTTP and, on Unix, in-session
+    /// transports support timeouts, others return `Error::Unsupported`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        match *self {
+            R2Pipe::Pipe(ref mut x) => x.set_timeout(timeout),
+            R2Pipe::Tcp(ref mut x) => x.set_timeout(timeout)?,
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => x.set_timeout(timeout)?,
+            #[cfg(feature = "http")]
+            R2Pipe::Http(ref mut x) => x.set_timeout(timeout)?,
+            R2Pipe::Lang(ref mut x) => x.set_timeout(timeout)?,
+            _ => return Err(Error::Unsupported),
+        }
+        Ok(())
+    }
+
+    /// Returns the timeout set with `set_timeout`, `None` if the transport
+    /// does not support any.
+    fn timeout(&self) -> Option<Option<Duration>> {
+        match *self {
+            R2Pipe::Pipe(ref x) => Some(x.read.get_ref().timeout()),
+            R2Pipe::Tcp(ref x) => Some(x.timeout),
+            #[cfg(unix)]
+            R2Pipe::Unix(ref x) => Some(x.timeout),
+            #[cfg(feature = "http")]
+            R2Pipe::Http(ref x) => Some(x.timeout),
+            #[cfg(unix)]
+            R2Pipe::Lang(ref x) => Some(x.read.get_ref().timeout),
+            _ => None,
+        }
+    }
+
+    /// Runs `cmd` like `cmd`, waiting at most `timeout` for its reply, then
+    /// restores the timeout set with `set_timeout`.
+    ///
+    /// See `set_timeout` for what happens to the pipe when the command times
+    /// out; with `with_recovery` a spawned r2 is started again right away,
+    /// the TCP and Unix socket transports are connected again on the next
+    /// command. Transports without timeouts
+    /// return `Error::Unsupported` without running `cmd`.
+    pub fn cmd_timeout(&mut self, cmd: &str, timeout: Duration) -> Result<String> {
+        let prev = self.timeout().ok_or(Error::Unsupported)?;
+        self.set_timeout(Some(timeout))?;
+        let res = self.cmd(cmd);
+        self.set_timeout(prev)?;
+        res
+    }
+
+    /// Returns whether r2 answers a trivial command within `ALIVE_TIMEOUT`,
+    /// where the transport supports timeouts, and a spawned r2 is still
+    /// running.
+    ///
+    /// A spawned r2 which does not answer in time is killed, see
+    /// `set_timeout`. Neither the cache nor `with_recovery` are involved.
+    pub fn is_alive(&mut self) -> bool {
+        if let R2Pipe::Pipe(ref mut x) = *self {
+            if !matches!(x.child.try_wait(), Ok(None)) {
+                return false;
+            }
+        }
+        let prev = self.timeout();
+        if prev.is_some() && self.set_timeout(Some(ALIVE_TIMEOUT)).is_err() {
+            return false;
+        }
+        let res = self.transport_cmd_once("?e ping");
+        if let Some(timeout) = prev {
+            let _ = self.set_timeout(timeout);
+        }
+        matches!(res, Ok(ref out) if out.starts_with(b"ping"))
+    }
+
+    /// Returns the address of the r2 server for the TCP transport, see
+    /// `R2PipeTcp::peer_addr`, `None` for the other ones.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            R2Pipe::Tcp(ref x) => Some(x.peer_addr()),
+            _ => None,
+        }
+    }
+
+    /// Returns what a spawned r2 has written to stderr so far, see
+    /// `R2PipeSpawn::last_stderr`, `None` for the other transports.
+    pub fn last_stderr(&self) -> Option<String> {
+        match *self {
+            R2Pipe::Pipe(ref x) => x.last_stderr(),
+            _ => None,
+        }
+    }
+
+    /// Returns and forgets what a spawned r2 has written to stderr, see
+    /// `R2PipeSpawn::take_stderr`, `None` for the other transports.
+    pub fn take_stderr(&mut self) -> Option<String> {
+        match *self {
+            R2Pipe::Pipe(ref mut x) => x.take_stderr(),
+            _ => None,
+        }
+    }
+
+    /// Makes `cmd` and the commands built on it return
+    /// `Error::CommandFailed` when the reply looks like an r2 error, see
+    /// `ERROR_PREFIXES`. Off by default, `cmd_bytes` is never affected.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.state().strict = strict;
+    }
+
+    /// Makes commands survive the loss of the connection to the r2 server,
+    /// see `with_recovery`.
+    pub fn with_reconnect(self, max_retries: u32, backoff: Duration) -> R2Pipe {
+        self.with_recovery(RecoveryPolicy {
+            max_retries,
+            backoff,
+            ..Default::default()
+        })
+    }
+
+    /// Makes commands survive the loss of the session, following `policy`.
+    ///
+    /// When a command fails because of the connection, or because the
+    /// stream ended, it is opened again, up to `max_retries` more times if
+    /// that fails too, waiting `backoff` before the first attempt and twice
+    /// as long before each next one. The `setup` commands are run on the
+    /// new session, `on_recover` is called and the command is then run once
+    /// more. Failures of the commands themselves, timeouts included, are
+    /// never retried, but a spawned r2 killed because a command timed out
+    /// is spawned again the same way, ready for the next command.
+    ///
+    /// This helps the TCP, Unix socket and HTTP transports, for HTTP the
+    /// request is simply made again, and r2 started by `R2Pipe::spawn`,
+    /// which is spawned again the same way if it crashed. The new session
+    /// starts afresh: the seek, the analysis and whatever `setup` does not
+    /// restore are lost.
+    ///
+    /// ```no_run
+    /// # use r2pipe::{R2Pipe, RecoveryPolicy};
+    /// # use std::sync::Arc;
+    /// # use std::time::Duration;
+    /// # fn main() -> r2pipe::Result<()> {
+    /// let mut r2p = R2Pipe::spawn("/bin/ls", None)?.with_recovery(RecoveryPolicy {
+    ///     max_retries: 3,
+    ///     backoff: Duration::from_millis(100),
+    ///     setup: vec!["e asm.bytes=false".to_owned(), "aa".to_owned()],
+    ///     on_recover: Some(Arc::new(|e| eprintln!("r2 restarted after: {}", e))),
+    /// });
+    /// println!("{}", r2p.cmd("pdf @ main")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_recovery(mut self, policy: RecoveryPolicy) -> R2Pipe {
+        self.state().recovery = Some(policy);
+        self
+    }
+
+    /// Caches the output of up to `capacity` read-only commands, so issuing
+    /// them again does not hit r2. See `cache::CACHEABLE_PREFIXES` for what
+    /// gets cached and when the cache is invalidated.
+    pub fn enable_cache(&mut self, capacity: usize) {
+        self.state().cache = Some(Cache::new(capacity));
+    }
+
+    /// Stops caching command outputs and drops the cached ones.
+    pub fn disable_cache(&mut self) {
+        self.state().cache = None;
+    }
+
+    /// Drops every cached command output.
+    pub fn clear_cache(&mut self) {
+        if let Some(ref mut c) = self.state().cache {
+            c.clear();
+        }
+    }
+
+    /// Starts timing the commands sent to r2 by `cmd`, `cmd_bytes`,
+    /// `cmd_batch` and the helpers built on them, failed ones and retries
+    /// included. Outputs served from the cache are not counted, neither are
+    /// `cmd_lines`, `cmd_until` and `cmd_with_binary`. Enabling metrics
+    /// again resets the figures.
+    pub fn enable_metrics(&mut self) {
+        self.state().metrics = Some(Metrics::default());
+    }
+
+    /// Stops timing commands and drops the figures.
+    pub fn disable_metrics(&mut self) {
+        self.state().metrics = None;
+    }
+
+    /// Returns how long the last timed command took, `None` until one ran or
+    /// when metrics are disabled.
+    pub fn last_cmd_duration(&self) -> Option<Duration> {
+        self.state_ref().metrics.and_then(|m| m.last)
+    }
+
+    /// Returns the time spent in every timed command.
+    pub fn total_cmd_time(&self) -> Duration {
+        self.state_ref()
+            .metrics
+            .map(|m| m.total)
+            .unwrap_or_default()
+    }
+
+    /// Returns how many commands were timed.
+    pub fn cmd_count(&self) -> u64 {
+        self.state_ref()
+            .metrics
+            .map(|m| m.count)
+            .unwrap_or_default()
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Closes every opened file (`o-*`) and opens `path` instead, keeping r2
+    /// running, which saves its startup time when going through many files.
+    ///
+    /// Returns `Error::CommandFailed` when `path` does not show up in `oj`
+    /// afterwards. Nothing is opened anymore then. The analysis and flags of
+    /// the previous files are not kept.
+    pub fn reopen<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let uri = match path.to_str() {
+            Some(uri) if !uri.contains('"') => uri,
+            _ => return Err(Error::InvalidCommand(path.display().to_string())),
+        };
+        self.cmd("o-*")?;
+        let open = format!("o \"{}\"", uri);
+        let out = self.cmd(&open)?;
+        let opened = match self.cmdj("oj")? {
+            Value::Array(files) => files.iter().any(|f| f["uri"] == uri),
+            _ => false,
+        };
+        if !opened {
+            return Err(self.command_failed(&open, out));
+        }
+        Ok(())
+    }
+
+    pub fn close(&mut self) {
+        match *self {
+            R2Pipe::Pipe(ref mut x) => {
+                let _ = x.close();
+            }
+            R2Pipe::Lang(ref mut x) => x.close(),
+            R2Pipe::Tcp(ref mut x) => x.close(),
+            #[cfg(unix)]
+            R2Pipe::Unix(ref mut x) => x.close(),
+            #[cfg(feature = "http")]
+            R2Pipe::Http(ref mut x) => x.close(),
+            #[cfg(feature = "ws")]
+            R2Pipe::Ws(ref mut x) => x.close(),
+            R2Pipe::Custom(ref mut x) => x.transport.close(),
+        }
+    }
+
+    /// Returns the file descriptors r2 passes in `R2PIPE_IN` and
+    /// `R2PIPE_OUT`, or rizin in `RZ_PIPE_IN` and `RZ_PIPE_OUT`.
+    pub fn in_session() -> Option<(i32, i32)> {
+        let fds = |k_in, k_out| {
+            let f_in = getenv(k_in);
+            let f_out = getenv(k_out);
+            if f_in < 0 || f_out < 0 {
+                return None;
+            }
+            Some((f_in, f_out))
+        };
+        fds("R2PIPE_IN", "R2PIPE_OUT").or_else(|| fds("RZ_PIPE_IN", "RZ_PIPE_OUT"))
+    }
+
+    /// Returns whether this program runs inside an r2 session, for the
+    /// platform, so that `open` can connect to it.
+    pub fn has_session() -> bool {
+        #[cfg(windows)]
+        return R2Pipe::in_windows_session().is_some();
+        #[cfg(not(windows))]
+        return R2Pipe::in_session().is_some();
+    }
+
+    /// Returns the named pipe r2 passes in `R2PIPE_PATH`, or rizin in
+    /// `RZ_PIPE_PATH`.
+    #[cfg(windows)]
+    pub fn in_windows_session() -> Option<String> {
+        match env::var("R2PIPE_PATH").or_else(|_| env::var("RZ_PIPE_PATH")) {
+            Ok(val) => Some(format!("\\\\.\\pipe\\{}", val)),
+            Err(_) => None,
+        }
+    }
+
+    /// Creates a new R2PipeCustom, sending commands through `transport`.
+    pub fn from_transport(transport: Box<dyn R2PipeTransport>) -> R2Pipe {
+        R2Pipe::Custom(R2PipeCustom {
+            transport,
+            state: PipeState::default(),
+        })
+    }
+
+    /// Starts building the options of a new R2PipeSpawn, see
+    /// `R2PipeSpawnOptions::builder`.
+    ///
+    /// ```no_run
+    /// # use r2pipe::R2Pipe;
+    /// # use std::time::Duration;
+    /// # fn main() -> r2pipe::Result<()> {
+    /// let mut r2p = R2Pipe::builder()
+    ///     .exepath("radare2")
+    ///     .arg("-2")
+    ///     .env("R2_NOPLUGINS", "1")
+    ///     .timeout(Duration::from_secs(5))
+    ///     .open("/bin/ls")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> R2PipeSpawnOptionsBuilder {
+        R2PipeSpawnOptions::builder()
+    }
+
+    /// Creates a new R2PipeSpawn.
+    pub fn spawn<T: AsRef<str>>(name: T, opts: Option<R2PipeSpawnOptions>) -> Result<R2Pipe> {
+        if name.as_ref() == "" && R2Pipe::has_session() {
+            return R2Pipe::open();
+        }
+
+        let mut opts = opts.unwrap_or_default();
+        if opts.exepath.is_empty() {
+            opts.exepath = "r2".to_owned();
+        }
+        let path = Path::new(name.as_ref());
+        let mut command = Command::new(&opts.exepath);
+        command.args(opts.r2_args()).arg(path);
+        command.envs(opts.env.iter().map(|(k, v)| (k, v)));
+        if let Some(ref dir) = opts.cwd {
+            command.current_dir(dir);
+        }
+        let mut res = R2Pipe::spawn_command(command, &opts)?;
+        if let R2Pipe::Pipe(ref mut x) = res {
+            x.origin = Some(Box::new((name.as_ref().to_owned(), opts)));
+        }
+        Ok(res)
+    }
+
+    /// Creates a new R2PipeSpawn running rizin, which speaks the same
+    /// protocol, rather than r2.
+    ///
+    /// Everything in `opts` applies as for `R2Pipe::spawn`, but for an empty
+    /// `exepath` which stands for `rizin`. Most commands and their JSON
+    /// output are the same, those which differ are not translated.
+    pub fn spawn_rizin<T: AsRef<str>>(name: T, opts: Option<R2PipeSpawnOptions>) -> Result<R2Pipe> {
+        let mut opts = opts.unwrap_or_default();
+        if opts.exepath.is_empty() {
+            opts.exepath = "rizin".to_owned();
+        }
+        R2Pipe::spawn(name, Some(opts))
+    }
+
+    /// Returns the path of the first `r2` executable found in `PATH`, or else
+    /// of the first `radare2`, or else of the first `rizin`.
+    pub fn which() -> Option<PathBuf> {
+        let dirs: Vec<PathBuf> = env::split_paths(&env::var_os("PATH")?).collect();
+        ["r2", "radare2", "rizin"].iter().find_map(|name| {
+            let exe = Path::new(name).with_extension(env::consts::EXE_EXTENSION);
+            dirs.iter().map(|d| d.join(&exe)).find(|p| p.is_file())
+        })
+    }
+
+    /// Creates a new R2PipeSpawn running `r2` with `args` passed verbatim.
+    ///
+    /// The arguments are placed after `-q0` and before `file`. This is the
+    /// low-level counterpart of `R2Pipe::spawn`, for command lines that can
+    /// not be expressed through `R2PipeSpawnOptions`.
+    pub fn spawn_args<P: AsRef<Path>, A: IntoIterator<Item = OsString>>(
+        file: P,
+        args: A,
+    ) -> Result<R2Pipe> {
+        let mut command = Command::new("r2");
+        command.arg("-q0").args(args).arg(file.as_ref());
+        R2Pipe::spawn_command(command, &R2PipeSpawnOptions::default())
+    }
+
+    fn spawn_command(mut command: Command, opts: &R2PipeSpawnOptions) -> Result<R2Pipe> {
+        if opts.capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+        let child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| spawn_error(command.get_program(), e))?;
+        let mut res = R2Pipe::attach(child, !opts.skip_handshake)?;
+        if let Some(timeout) = opts.timeout {
+            res.set_timeout(Some(timeout))?;
+        }
+        Ok(res)
+    }
+
+    /// Creates a new R2PipeSpawn talking to an r2 started by the caller,
+    /// for when `R2Pipe::spawn` can not set it up as needed.
+    ///
+    /// r2 must have been given `-q0` and have its stdin and stdout piped.
+    /// If its stderr is piped too, it is captured as with
+    /// `R2PipeSpawnOptions::capture_stderr`.
+    pub fn from_child(child: process::Child) -> Result<R2Pipe> {
+        R2Pipe::attach(child, true)
+    }
+
+    /// Wraps `child`, waiting for its first NUL byte if `handshake` is set.
+    fn attach(mut child: process::Child, handshake: bool) -> Result<R2Pipe> {
+        let (sin, sout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(sin), Some(sout)) => (sin, sout),
+            _ => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "stdin and stdout of r2 must be piped",
+                )))
+            }
+        };
+
+        // r2 may write more than a pipe holds, so stderr is drained all along
+        let stderr = child.stderr.take().map(|mut serr| {
+            let buf = Arc::new(Mutex::new(Vec::new()));
+            let sink = Arc::clone(&buf);
+            let handle = thread::spawn(move || {
+                let mut chunk = [0u8; 4096];
+                while let Ok(n) = serr.read(&mut chunk) {
+                    if n == 0 {
+                        break;
+                    }
+                    sink.lock().unwrap().extend_from_slice(&chunk[..n]);
+                }
+            });
+            (buf, handle)
+        });
+
+        // flush out everything up to the initial null byte, including any
+        // banner printed by a wrapper script
+        let mut read = BufReader::new(SpawnOut::Direct(sout));
+        let mut banner = Vec::new();
+        if handshake {
+            read.read_until(0u8, &mut banner)?;
+            if banner.last() != Some(&0) {
+                if let Some((buf, handle)) = stderr {
+                    // r2 gave up before the session started, the reason is on stderr
+                    let _ = child.wait();
+                    let _ = handle.join();
+                    let text = String::from_utf8_lossy(&buf.lock().unwrap())
+                        .trim()
+                        .to_owned();
+                    return Err(Error::StartupFailed(text));
+                }
+                // r2 may not have exited, it must not be left behind
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::StartupFailed(NO_HANDSHAKE.to_owned()));
+            }
+        }
+
+        let res = R2PipeSpawn {
+            child,
+            read,
+            write: Some(sin),
+            stderr: stderr.map(|(buf, _)| buf),
+            origin: None,
+            state: PipeState::default(),
+        };
+
+        Ok(R2Pipe::Pipe(res))
+    }
+
+    /// Creates a new R2PipeTcp
+    pub fn tcp<A: ToSocketAddrs>(addr: A) -> Result<R2Pipe> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let stream = TcpStream::connect(&addrs[..])?;
+        R2Pipe::tcp_stream(stream, addrs)
+    }
+
+    /// Creates a new R2PipeTcp, giving up connecting after `timeout`.
+    pub fn tcp_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<R2Pipe> {
+        R2Pipe::tcp_retry(addr, timeout, 0)
+    }
+
+    /// Creates a new R2PipeTcp like `tcp_timeout`, trying again up to
+    /// `retries` times while the server is not listening yet. The delay
+    /// between attempts starts at 100ms and doubles each time.
+    pub fn tcp_retry<A: ToSocketAddrs>(addr: A, timeout: Duration, retries: u32) -> Result<R2Pipe> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let mut delay = Duration::from_millis(100);
+        let mut attempt = 0;
+        loop {
+            let mut err = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+            for a in &addrs {
+                match TcpStream::connect_timeout(a, timeout) {
+                    Ok(stream) => return R2Pipe::tcp_stream(stream, addrs),
+                    Err(e) => err = e,
+                }
+            }
+            if attempt == retries {
+                return Err(err.into());
+            }
+            attempt += 1;
+            thread::sleep(delay);
+            delay = delay.checked_mul(2).unwrap_or(delay);
+        }
+    }
+
+    /// Creates a new R2PipeUnix, connected to the r2 server listening on
+    /// the Unix socket at `path`.
+    #[cfg(unix)]
+    #[cfg_attr(doc_cfg, doc(cfg(unix)))]
+    pub fn unix<P: AsRef<Path>>(path: P) -> Result<R2Pipe> {
+        let stream = UnixStream::connect(path.as_ref())?;
+        Ok(R2Pipe::Unix(R2PipeSocket::new(
+            stream,
+            path.as_ref().to_owned(),
+        )))
+    }
+
+    fn tcp_stream(stream: TcpStream, addrs: Vec<SocketAddr>) -> Result<R2Pipe> {
+        // the connection is reused by every command, unless r2 closes it
+        let peer = stream.peer_addr()?;
+        Ok(R2Pipe::Tcp(R2PipeSocket::new(stream, (addrs, peer))))
+    }
+
+    #[cfg(feature = "http")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
+    /// Creates a new R2PipeHttp, sending requests to `host`, eg.
+    /// `localhost:9090` or `http://localhost:9090`.
+    ///
+    /// A single client is built here and reused by every command, so the
+    /// connection to the r2 web server is kept alive between commands. If
+    /// it can not be built, the first command builds it again and reports
+    /// why it failed. Replies compressed with gzip or deflate, eg. by a
+    /// proxy in front of it, are decoded.
+    pub fn http(host: &str) -> R2Pipe {
+        let opts = R2PipeHttpOptions::default();
+        let mut res = R2Pipe::http_pipe(host, opts);
+        if let R2Pipe::Http(ref mut x) = res {
+            x.client = http_client(x.timeout, &x.opts).ok();
+        }
+        res
+    }
+
+    #[cfg(feature = "http")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
+    /// Creates a new R2PipeHttp whose requests carry the headers and
+    /// credentials of `opts`, eg. for a server behind a reverse proxy.
+    ///
+    /// ```no_run
+    /// # use r2pipe::{R2Pipe, R2PipeHttpOptions};
+    /// # fn main() -> r2pipe::Result<()> {
+    /// let opts = R2PipeHttpOptions {
+    ///     https: true,
+    ///     bearer_token: Some("s3cr3t".to_owned()),
+    ///     ..Default::default()
+    /// };
+    /// let mut r2p = R2Pipe::http_with("r2.example.com", opts)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn http_with(host: &str, opts: R2PipeHttpOptions) -> Result<R2Pipe> {
+        let mut res = R2Pipe::http_pipe(host, opts);
+        if let R2Pipe::Http(ref mut x) = res {
+            x.client = Some(http_client(x.timeout, &x.opts)?);
+        }
+        Ok(res)
+    }
+
+    /// Returns an R2PipeHttp which has no client yet. `host` is taken as is
+    /// when it has a scheme, `opts.https` picks it otherwise.
+    #[cfg(feature = "http")]
+    fn http_pipe(host: &str, opts: R2PipeHttpOptions) -> R2Pipe {
+        let base = if host.contains("://") {
+            host.trim_end_matches('/').to_owned()
+        } else {
+            let scheme = if opts.https { "https" } else { "http" };
+            format!("{}://{}", scheme, host)
+        };
+        let timeout = opts.timeout.unwrap_or(HTTP_TIMEOUT);
+        R2Pipe::Http(R2PipeHttp {
+            base,
+            client: None,
+            opts,
+            timeout: Some(timeout),
+            state: PipeState::default(),
+        })
+    }
+
+    #[cfg(feature = "ws")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "ws")))]
+    /// Creates a new R2PipeWs connected to `url` (`ws://host:port/...`).
+    ///
+    /// `wss://` URLs require enabling one of the TLS features of
+    /// `tungstenite`.
+    pub fn ws(url: &str) -> Result<R2Pipe> {
+        let (socket, _) = tungstenite::connect(url)?;
+        Ok(R2Pipe::Ws(R2PipeWs {
+            socket: Box::new(socket),
+            state: PipeState::default(),
+        }))
+    }
+
+    /// Creates new pipe threads
+    /// First two arguments for R2Pipe::threads() are the same as for R2Pipe::spawn() but inside vectors
+    /// Third and last argument is an option to a callback function
+    /// The callback function takes two Arguments: Thread ID and r2pipe output
+    /// It runs on the worker thread, in the order commands were sent, with the `id` of the R2PipeThread
+    ///
+    /// See `R2Threads` to add and remove pipe threads while in use.
+    pub fn threads<T: Into<String>>(
+        names: Vec<T>,
+        opts: Vec<Option<R2PipeSpawnOptions>>,
+        callback: Option<Arc<dyn Fn(u16, String) + Sync + Send>>,
+    ) -> Result<Vec<R2PipeThread>> {
+        R2Pipe::spawn_threads(names, opts, callback, None)
+    }
+
+    /// Creates new pipe threads like `R2Pipe::threads`, each queueing at most
+    /// `capacity` commands not run yet.
+    ///
+    /// `R2PipeThread::send` then blocks while the queue of the worker is
+    /// full, and `R2PipeThread::try_send` fails with `Error::ChannelFull`.
+    /// Replies are still queued without bound until received.
+    pub fn threads_bounded<T: Into<String>>(
+        names: Vec<T>,
+        opts: Vec<Option<R2PipeSpawnOptions>>,
+        callback: Option<Arc<dyn Fn(u16, String) + Sync + Send>>,
+        capacity: usize,
+    ) -> Result<Vec<R2PipeThread>> {
+        R2Pipe::spawn_threads(names, opts, callback, Some(capacity))
+    }
+
+    fn spawn_threads<T: Into<String>>(
+        names: Vec<T>,
+        opts: Vec<Option<R2PipeSpawnOptions>>,
+        callback: Option<Arc<dyn Fn(u16, String) + Sync + Send>>,
+        capacity: Option<usize>,
+    ) -> Result<Vec<R2PipeThread>> {
+        if names.len() != opts.len() {
+            return Err(Error::ArgumentMismatch);
+        }
+
+        Ok(names
+            .into_iter()
+            .zip(opts)
+            .enumerate()
+            .map(|(n, (name, opt))| {
+                R2PipeThread::spawn(n as u16, name.into(), opt, callback.clone(), capacity)
+            })
+            .collect())
+    }
+}
+
+impl R2PipeThread {
+    fn spawn(
+        id: u16,
+        name: String,
+        opt: Option<R2PipeSpawnOptions>,
+        cb: Option<Arc<dyn Fn(u16, String) + Sync + Send>>,
+        capacity: Option<usize>,
+    ) -> R2PipeThread {
+        let (htx, rx) = mpsc::channel();
+        let (tx, hrx) = match capacity {
+            Some(capacity) => {
+                let (tx, hrx) = mpsc::sync_channel(capacity);
+                (ThreadSender::Bounded(tx), hrx)
+            }
+            None => {
+                let (tx, hrx) = mpsc::channel();
+                (ThreadSender::Unbounded(tx), hrx)
+            }
+        };
+        let (ptx, prx) = mpsc::channel();
+        let t = thread::spawn(move || -> Result<()> {
+            let mut r2 = R2Pipe::spawn(name, opt)?;
+            // a dropped R2PipeThread stops the worker like a shutdown
+            while let Ok(ThreadMsg::Cmd(cmd_id, cmd)) = hrx.recv() {
+                #[cfg(feature = "json")]
+                let res = r2.cmdj(&cmd)?.to_string();
+                #[cfg(not(feature = "json"))]
+                let res = r2.cmd(&cmd)?;
+                let reply = Response {
+                    id: cmd_id,
+                    cmd,
+                    output: res.clone(),
+                };
+                if htx.send(reply).is_err() {
+                    break;
+                }
+                // run on the worker so replies are seen in order
+                if let Some(ref cbs) = cb {
+                    let ret = panic::catch_unwind(AssertUnwindSafe(|| cbs(id, res)));
+                    if let Err(e) = ret {
+                        let _ = ptx.send(panic_message(e));
+                    }
+                }
+            }
+            r2.close();
+            Ok(())
+        });
+        R2PipeThread {
+            r2recv: rx,
+            r2send: tx,
+            pending: RefCell::new(VecDeque::new()),
+            next_cmd: Cell::new(0),
+            received: Cell::new(0),
+            r2panic: prx,
+            id,
+            handle: t,
+        }
+    }
+
+    pub fn send(&self, cmd: String) -> Result<()> {
+        self.submit(cmd).map(|_| ())
+    }
+
+    /// Sends `cmd` like `send`, but fails with `Error::ChannelFull` instead
+    /// of blocking when the queue of a bounded worker is full.
+    pub fn try_send(&self, cmd: String) -> Result<()> {
+        self.try_submit(cmd).map(|_| ())
+    }
+
+    /// Sends `cmd` like `send` and returns its id, to get its reply with
+    /// `recv_for` or tell it apart in `recv_response`.
+    pub fn submit(&self, cmd: String) -> Result<CommandId> {
+        let id = CommandId(self.next_cmd.get());
+        self.r2send
+            .send(ThreadMsg::Cmd(id, cmd))
+            .map_err(|e| match e.0 {
+                ThreadMsg::Cmd(_, cmd) => mpsc::SendError(cmd).into(),
+                ThreadMsg::Shutdown => Error::ConnectionClosed,
+            })?;
+        self.next_cmd.set(id.0 + 1);
+        Ok(id)
+    }
+
+    /// Sends `cmd` like `try_send` and returns its id, see `submit`.
+    pub fn try_submit(&self, cmd: String) -> Result<CommandId> {
+        let id = CommandId(self.next_cmd.get());
+        self.r2send
+            .try_send(ThreadMsg::Cmd(id, cmd))
+            .map_err(|e| match e {
+                mpsc::TrySendError::Full(ThreadMsg::Cmd(_, cmd)) => Error::ChannelFull(cmd),
+                mpsc::TrySendError::Disconnected(ThreadMsg::Cmd(_, cmd)) => {
+                    mpsc::SendError(cmd).into()
+                }
+                _ => Error::ConnectionClosed,
+            })?;
+        self.next_cmd.set(id.0 + 1);
+        Ok(id)
+    }
+
+    /// Stops the worker once the commands already sent are done, closing its
+    /// r2, and waits for it. Returns the error that stopped the worker early,
+    /// if any.
+    pub fn shutdown(self) -> Result<()> {
+        self.signal_shutdown();
+        self.join()
+    }
+
+    /// Shuts every worker of `threads` down like `shutdown`, letting them
+    /// all finish at once, and returns their results in the same order.
+    pub fn join_all(threads: Vec<R2PipeThread>) -> Vec<Result<()>> {
+        for t in &threads {
+            t.signal_shutdown();
+        }
+        threads.into_iter().map(R2PipeThread::join).collect()
+    }
+
+    fn signal_shutdown(&self) {
+        // the worker may be gone already, joining tells why
+        let _ = self.r2send.send(ThreadMsg::Shutdown);
+    }
+
+    fn join(self) -> Result<()> {
+        match self.handle.join() {
+            Ok(res) => res,
+            Err(e) => Err(Error::WorkerPanicked(panic_message(e))),
+        }
+    }
+
+    pub fn recv(&self, block: bool) -> Result<String> {
+        self.recv_response(block).map(|res| res.output)
+    }
+
+    /// Returns the next reply like `recv`, along with the command it answers.
+    pub fn recv_response(&self, block: bool) -> Result<Response> {
+        if let Some(res) = self.pending.borrow_mut().pop_front() {
+            return Ok(res);
+        }
+        let res = if block {
+            self.r2recv.recv()?
+        } else {
+            self.r2recv.try_recv()?
+        };
+        self.received.set(self.received.get() + 1);
+        Ok(res)
+    }
+
+    /// Blocks until the reply to the command `id` comes and returns it.
+    ///
+    /// Replies to the commands sent before it are kept for the next
+    /// receives. Fails with `Error::UnknownCommand` when `id` was not sent
+    /// to this worker or its reply was already received.
+    pub fn recv_for(&self, id: CommandId) -> Result<String> {
+        let mut pending = self.pending.borrow_mut();
+        let kept = pending.iter().position(|res| res.id == id);
+        if let Some(res) = kept.and_then(|pos| pending.remove(pos)) {
+            return Ok(res.output);
+        }
+        if id.0 < self.received.get() || id.0 >= self.next_cmd.get() {
+            return Err(Error::UnknownCommand(id.0));
+        }
+        loop {
+            let res = self.r2recv.recv()?;
+            self.received.set(self.received.get() + 1);
+            if res.id == id {
+                return Ok(res.output);
+            }
+            pending.push_back(res);
+        }
+    }
+
+    /// Returns the next reply if there is one, without blocking.
+    ///
+    /// Unlike `recv(false)`, this tells a worker that is busy from one that
+    /// stopped: `shutdown` then reports why it did.
+    pub fn poll(&self) -> Poll {
+        match self.recv_response(false) {
+            Ok(res) => Poll::Ready(res.output),
+            Err(Error::ChannelTryReceiveError(mpsc::TryRecvError::Disconnected)) => Poll::Closed,
+            Err(_) => Poll::Pending,
+        }
+    }
+
+    /// Returns whether the worker stopped.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Returns the message of a panic raised by the callback, if any.
+    ///
+    /// A panicking callback does not stop the worker, which goes on with the
+    /// next command. Each call yields the next pending panic message.
+    pub fn callback_panic(&self) -> Option<String> {
+        self.r2panic.try_recv().ok()
+    }
+}
+
+/// A set of pipe threads which can grow and shrink while in use, each
+/// identified by the `id` it was given when added.
+///
+/// ```no_run
+/// # use r2pipe::r2pipe::R2Threads;
+/// # fn main() -> r2pipe::Result<()> {
+/// let mut threads = R2Threads::new(None);
+/// for path in std::env::args().skip(1) {
+///     threads.add_pipe(path, None);
+/// }
+/// threads.broadcast("ij")?;
+/// for t in threads.iter() {
+///     println!("Pipe #{} says: {}", t.id, t.recv(true)?);
+/// }
+/// for res in threads.join_all() {
+///     res?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct R2Threads {
+    threads: Vec<R2PipeThread>,
+    callback: Option<Arc<dyn Fn(u16, String) + Sync + Send>>,
+    capacity: Option<usize>,
+    next_id: u16,
+}
+
+impl R2Threads {
+    /// Creates an empty set, `callback` being given to every pipe thread
+    /// added, see `R2Pipe::threads`.
+    pub fn new(callback: Option<Arc<dyn Fn(u16, String) + Sync + Send>>) -> R2Threads {
+        R2Threads {
+            threads: Vec::new(),
+            callback,
+            capacity: None,
+            next_id: 0,
+        }
+    }
+
+    /// Creates an empty set whose pipe threads each queue at most `capacity`
+    /// commands, see `R2Pipe::threads_bounded`.
+    pub fn bounded(
+        callback: Option<Arc<dyn Fn(u16, String) + Sync + Send>>,
+        capacity: usize,
+    ) -> R2Threads {
+        R2Threads {
+            capacity: Some(capacity),
+            ..R2Threads::new(callback)
+        }
+    }
+
+    /// Spawns a pipe thread on `name`, see `R2Pipe::spawn`, and returns its
+    /// id. Ids are not reused once their thread is removed.
+    ///
+    /// Spawning r2 happens on the worker: a failure stops it, and is
+    /// reported when it is removed or joined.
+    pub fn add_pipe<T: Into<String>>(&mut self, name: T, opt: Option<R2PipeSpawnOptions>) -> u16 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.threads.push(R2PipeThread::spawn(
+            id,
+            name.into(),
+            opt,
+            self.callback.clone(),
+            self.capacity,
+        ));
+        id
+    }
+
+    /// Returns the pipe thread `id`, if it was not removed.
+    pub fn get(&self, id: u16) -> Option<&R2PipeThread> {
+        self.threads.iter().find(|t| t.id == id)
+    }
+
+    /// Iterates over the pipe threads, in the order they were added.
+    pub fn iter(&self) -> std::slice::Iter<'_, R2PipeThread> {
+        self.threads.iter()
+    }
+
+    /// Returns the number of pipe threads.
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns whether there are no pipe threads.
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Takes the pipe thread `id` out of the set and shuts it down, see
+    /// `R2PipeThread::shutdown`. Returns `None` when there is no such thread.
+    ///
+    /// Its unread replies are dropped.
+    pub fn remove(&mut self, id: u16) -> Option<Result<()>> {
+        let pos = self.threads.iter().position(|t| t.id == id)?;
+        Some(self.threads.remove(pos).shutdown())
+    }
+
+    /// Sends `cmd` to every pipe thread, see `R2PipeThread::send`. It is
+    /// sent to all of them even when some fail, the first error being
+    /// returned.
+    pub fn broadcast(&self, cmd: &str) -> Result<()> {
+        let mut res = Ok(());
+        for t in &self.threads {
+            let sent = t.send(cmd.to_string());
+            if res.is_ok() {
+                res = sent;
+            }
+        }
+        res
+    }
+
+    /// Shuts every pipe thread down, see `R2PipeThread::join_all`.
+    pub fn join_all(self) -> Vec<Result<()>> {
+        R2PipeThread::join_all(self.threads)
+    }
+}
+
+impl R2PipeSpawn {
+    fn writer(&mut self) -> Result<&mut process::ChildStdin> {
+        self.write.as_mut().ok_or(Error::NoSession)
+    }
+
+    /// Reads a reply up to `delim`, the NUL terminator but for `cmd_until`.
+    ///
+    /// When the configured timeout elapses first r2 is killed, as there is no
+    /// way to tell where its late reply would end, and
+    /// `Error::CommandTimeout` is returned. Later commands fail with
+    /// `Error::NoSession`.
+    fn read_reply(&mut self, delim: u8) -> Result<Vec<u8>> {
+        let mut res: Vec<u8> = Vec::new();
+        match self.read.read_until(delim, &mut res) {
+            Ok(_) => Ok(res),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                let still_running = matches!(self.child.try_wait(), Ok(None));
+                let _ = self.kill();
+                Err(Error::CommandTimeout { still_running })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the process id of r2.
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Kills r2 and waits for it to exit. Later commands fail with
+    /// `Error::NoSession`.
+    pub fn kill(&mut self) -> Result<()> {
+        self.write = None;
+        *self.read.get_mut() = SpawnOut::Closed;
+        self.child.kill()?;
+        self.child.wait()?;
+        Ok(())
+    }
+
+    /// Sets how long to wait for each reply, `None` waits forever. It also
+    /// applies once r2 is spawned again by `R2Pipe::with_recovery`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.read.get_mut().set_timeout(timeout);
+        if let Some(ref mut origin) = self.origin {
+            origin.1.timeout = timeout;
+        }
+    }
+
+    /// Returns the reader of r2's stdout, to speak the pipe protocol
+    /// directly: r2 ends each reply with a NUL byte.
+    ///
+    /// Anything read or left unread here is not seen by `cmd` and the other
+    /// methods, which then mistake the rest of a reply for the one of the
+    /// next command. Replies must be read in full before using them again.
+    pub fn raw_reader(&mut self) -> &mut impl BufRead {
+        &mut self.read
+    }
+
+    /// Returns the writer of r2's stdin, see `raw_reader`. Each command
+    /// written must end with a newline. Fails with `Error::NoSession` once
+    /// r2 was closed or killed.
+    pub fn raw_writer(&mut self) -> Result<&mut impl Write> {
+        self.writer()
+    }
+
+    /// Returns what r2 has written to stderr so far, or `None` unless it was
+    /// spawned with `R2PipeSpawnOptions::capture_stderr`.
+    pub fn last_stderr(&self) -> Option<String> {
+        let buf = self.stderr.as_ref()?.lock().unwrap();
+        Some(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Returns what r2 has written to stderr since the last call, like
+    /// `last_stderr`, and forgets it, eg. to tell which command a warning
+    /// comes from.
+    pub fn take_stderr(&mut self) -> Option<String> {
+        let mut buf = self.stderr.as_ref()?.lock().unwrap();
+        Some(String::from_utf8_lossy(&mem::take(&mut *buf)).into_owned())
+    }
+
+    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd)?)
+    }
+
+    pub fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        let cmd = cmd.to_owned() + "\n";
+        self.writer()?.write_all(cmd.as_bytes())?;
+
+        let res = self.read_reply(0)?;
+        process_result(&cmd, res, 0)
+    }
+
+    /// Runs `cmd` and returns its output line by line, see `R2Pipe::cmd_lines`.
+    pub fn cmd_lines(&mut self, cmd: &str) -> Result<Lines<'_>> {
+        let cmd = cmd.to_owned() + "\n";
+        self.writer()?.write_all(cmd.as_bytes())?;
+        Ok(Lines::new(&mut self.read))
+    }
+
+    /// Runs `cmd` and reads its output up to `delim`, see `R2Pipe::cmd_until`.
+    pub fn cmd_until(&mut self, cmd: &str, delim: u8) -> Result<String> {
+        let cmd = cmd.to_owned() + "\n";
+        self.writer()?.write_all(cmd.as_bytes())?;
+        let res = self.read_reply(delim)?;
+        into_string(process_result(&cmd, res, delim)?)
+    }
+
+    pub fn cmd_with_binary(&mut self, cmd: &str, payload: &[u8]) -> Result<String> {
+        let w = self.writer()?;
+        w.write_all(cmd.as_bytes())?;
+        w.write_all(b"\n")?;
+        w.write_all(payload)?;
+
+        let res = self.read_reply(0)?;
+        into_string(process_result(cmd, res, 0)?)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
+        self.cmd_typed(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmd_typed<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<T> {
+        let res = self.cmd_bytes(cmd)?;
+        parse_json(cmd, &res)
+    }
+
+    /// Sends `q!` and closes r2's stdin, so it exits even if the quit
+    /// command was not seen, then waits for it. No reply is read, r2 may be
+    /// busy with a command. r2 is killed if it is still running after a
+    /// moment. Later commands fail with `Error::NoSession`.
+    ///
+    /// Returns how r2 exited, which tells whether it crashed.
+    pub fn close(&mut self) -> Result<process::ExitStatus> {
+        if let Some(mut w) = self.write.take() {
+            // nor is r2 reading its stdin, whose pipe may be full
+            #[cfg(unix)]
+            set_nonblocking(&w, true);
+            let _ = w.write_all(b"q!\n").and_then(|_| w.flush());
+        }
+        Ok(self.reap(EXIT_TIMEOUT)?)
+    }
+
+    /// Kills r2 if it still runs and starts it again like `R2Pipe::spawn`
+    /// did, for `R2Pipe::with_recovery`. The settings of the pipe are kept,
+    /// what r2 wrote to stderr is not.
+    fn respawn(&mut self) -> Result<()> {
+        let (name, opts) = *self.origin.clone().ok_or(Error::Unsupported)?;
+        let _ = self.kill();
+        match R2Pipe::spawn(name, Some(opts))? {
+            R2Pipe::Pipe(mut new) => {
+                mem::swap(&mut new.state, &mut self.state);
+                mem::swap(&mut new, self);
+                Ok(())
+            }
+            // only with an empty name, which opens the session instead
+            _ => Err(Error::Unsupported),
+        }
+    }
+
+    /// Waits up to `timeout` for r2 to exit, killing it past that.
+    fn reap(&mut self, timeout: Duration) -> io::Result<process::ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                let _ = self.child.kill();
+                return self.child.wait();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Time given to r2 to exit on its own before being killed.
+pub(crate) const EXIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Makes writes to `fd` fail rather than wait while its pipe is full.
+#[cfg(unix)]
+fn set_nonblocking<F: std::os::unix::io::AsRawFd>(fd: &F, nonblocking: bool) {
+    let fd = fd.as_raw_fd();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags >= 0 {
+            let flags = if nonblocking {
+                flags | libc::O_NONBLOCK
+            } else {
+                flags & !libc::O_NONBLOCK
+            };
+            libc::fcntl(fd, libc::F_SETFL, flags);
+        }
+    }
+}
+
+impl Drop for R2PipeSpawn {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl R2PipeLang {
+    fn writer(&mut self) -> &mut File {
+        match self.write {
+            Some(ref mut w) => w,
+            None => &mut self.read.get_mut().file,
+        }
+    }
+
+    /// Skips the reply to the command which timed out, if it was not read
+    /// yet, so that it is not mistaken for the next one. Fails with
+    /// `Error::Timeout`, sending nothing, while r2 is still running it.
+    fn sync(&mut self) -> Result<()> {
+        if self.stale {
+            let mut late = Vec::new();
+            self.read_reply(0, &mut late)?;
+            self.stale = false;
+        }
+        Ok(())
+    }
+
+    /// Reads a reply up to `delim` into `res`, remembering that the rest of
+    /// it must be skipped when it times out.
+    fn read_reply(&mut self, delim: u8, res: &mut Vec<u8>) -> Result<()> {
+        match self.read.read_until(delim, res) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                self.stale = true;
+                Err(Error::Timeout)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sets how long to wait for each reply, `None` waits forever. A reply
+    /// which arrives late is skipped before sending the next command. Only
+    /// supported on Unix, elsewhere `Error::Unsupported` is returned.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if cfg!(not(unix)) && timeout.is_some() {
+            return Err(Error::Unsupported);
+        }
+        self.read.get_mut().timeout = timeout;
+        Ok(())
+    }
+
+    /// Returns the reader of the pipe to r2, see `R2PipeSpawn::raw_reader`.
+    pub fn raw_reader(&mut self) -> &mut impl BufRead {
+        &mut self.read
+    }
+
+    /// Returns the writer of the pipe to r2, see `R2PipeSpawn::raw_writer`.
+    /// Commands need no trailing newline here.
+    pub fn raw_writer(&mut self) -> &mut impl Write {
+        self.writer()
+    }
+
+    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd)?)
+    }
+
+    pub fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        self.sync()?;
+        self.writer().write_all(cmd.as_bytes())?;
+        let mut res: Vec<u8> = Vec::new();
+        self.read_reply(0u8, &mut res)?;
+        process_result(cmd, res, 0)
+    }
+
+    /// Runs `cmd` and returns its output line by line, see `R2Pipe::cmd_lines`.
+    pub fn cmd_lines(&mut self, cmd: &str) -> Result<Lines<'_>> {
+        self.sync()?;
+        self.writer().write_all(cmd.as_bytes())?;
+        Ok(Lines::new(&mut self.read))
+    }
+
+    /// Runs `cmd` and reads its output up to `delim`, see `R2Pipe::cmd_until`.
+    pub fn cmd_until(&mut self, cmd: &str, delim: u8) -> Result<String> {
+        self.sync()?;
+        self.writer().write_all(cmd.as_bytes())?;
+        let mut res: Vec<u8> = Vec::new();
+        self.read_reply(delim, &mut res)?;
+        into_string(process_result(cmd, res, delim)?)
+    }
+
+    pub fn cmd_with_binary(&mut self, cmd: &str, payload: &[u8]) -> Result<String> {
+        self.sync()?;
+        let w = self.writer();
+        w.write_all(cmd.as_bytes())?;
+        w.write_all(b"\n")?;
+        w.write_all(payload)?;
+
+        let mut res: Vec<u8> = Vec::new();
+        self.read_reply(0u8, &mut res)?;
+        into_string(process_result(cmd, res, 0)?)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
+        self.cmd_typed(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmd_typed<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<T> {
+        let res = self.cmd_bytes(cmd)?;
+        parse_json(cmd, &res)
+    }
+
+    pub fn close(&mut self) {
+        // self.read.close();
+        // self.write.close();
+    }
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "http")))]
+impl R2PipeHttp {
+    /// Sets how long to wait for each reply, `None` waits forever. The client
+    /// is built again, which drops the connection kept alive.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.client = Some(http_client(timeout, &self.opts)?);
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd)?)
+    }
+
+    pub fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        let url = format!("{}/cmd/{}", self.base, percent_encode(cmd));
+        let client = match self.client {
+            Some(ref client) => client,
+            None => self.client.insert(http_client(self.timeout, &self.opts)?),
+        };
+        let mut req = client.get(&url);
+        // compressed replies are decoded below, unless the caller asked for
+        // an encoding of their own
+        if !self
+            .opts
+            .headers
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case(ACCEPT_ENCODING.as_str()))
+        {
+            req = req.header(ACCEPT_ENCODING, "gzip, deflate");
+        }
+        for (k, v) in &self.opts.headers {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        if let Some((ref user, ref pass)) = self.opts.basic_auth {
+            req = req.basic_auth(user, pass.as_ref());
+        }
+        if let Some(ref token) = self.opts.bearer_token {
+            req = req.bearer_auth(token);
+        }
+        let mut res = req.send().map_err(|e| {
+            if e.is_timeout() {
+                Error::Timeout
+            } else {
+                e.into()
+            }
+        })?;
+        let encoding = match res.headers().get(CONTENT_ENCODING) {
+            Some(v) => v.to_str().unwrap_or_default().trim().to_ascii_lowercase(),
+            None => String::new(),
+        };
+        let mut bytes = Vec::new();
+        match encoding.as_str() {
+            "" | "identity" => res.read_to_end(&mut bytes)?,
+            "gzip" | "x-gzip" => GzDecoder::new(res).read_to_end(&mut bytes)?,
+            "deflate" => ZlibDecoder::new(res).read_to_end(&mut bytes)?,
+            _ => {
+                return Err(Error::UnexpectedResponse(format!(
+                    "unsupported content encoding {}",
+                    encoding
+                )))
+            }
+        };
+        Ok(bytes)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
+        self.cmd_typed(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmd_typed<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<T> {
+        let res = self.cmd_bytes(cmd)?;
+        parse_json(cmd, &res)
+    }
+
+    pub fn close(&mut self) {}
+}
+
+impl<S: Socket> R2PipeSocket<S> {
+    fn new(stream: S, addr: S::Addr) -> R2PipeSocket<S> {
+        R2PipeSocket {
+            stream: Some(BufReader::new(stream)),
+            addr,
+            timeout: None,
+            answered: false,
+            reopen: false,
+            state: PipeState::default(),
+        }
+    }
+
+    fn stream(&mut self) -> Result<&mut BufReader<S>> {
+        if self.stream.is_none() && self.reopen {
+            self.reconnect()?;
+        }
+        self.stream.as_mut().ok_or(Error::ConnectionClosed)
+    }
+
+    /// Sends `parts` to r2. The connection is dropped if that fails, eg.
+    /// because r2 closed it, and `Error::ConnectionClosed` returned.
+    fn send(&mut self, parts: &[&[u8]]) -> Result<()> {
+        let w = self.stream()?.get_mut();
+        if parts.iter().try_for_each(|p| w.write_all(p)).is_err() {
+            self.stream = None;
+            return Err(Error::ConnectionClosed);
+        }
+        Ok(())
+    }
+
+    /// Replaces the connection with a new one to the same address.
+    fn reconnect(&mut self) -> Result<()> {
+        let stream = S::connect(&mut self.addr)?;
+        stream.set_read_timeout(self.timeout)?;
+        self.stream = Some(BufReader::new(stream));
+        self.answered = false;
+        self.reopen = false;
+        Ok(())
+    }
+
+    /// Reads a reply up to `delim`, the NUL terminator but for `cmd_until`,
+    /// see `end_reply` for replies ended by the server closing the
+    /// connection.
+    ///
+    /// The connection is dropped if the timeout elapses, as a late reply
+    /// would be mistaken for the one of the next command. Later commands
+    /// fail with `Error::ConnectionClosed`, as when r2 closed the connection
+    /// before replying.
+    fn read_reply(&mut self, delim: u8) -> Result<Vec<u8>> {
+        let mut res: Vec<u8> = Vec::new();
+        let ret = self.stream()?.read_until(delim, &mut res);
+        match ret {
+            Ok(_) => match end_reply(&mut res, delim, self.answered) {
+                ReplyEnd::Terminated => {
+                    self.answered = true;
+                    Ok(res)
+                }
+                ReplyEnd::Closed => {
+                    self.stream = None;
+                    self.reopen = true;
+                    Ok(res)
+                }
+                ReplyEnd::Lost => {
+                    self.stream = None;
+                    Err(Error::ConnectionClosed)
+                }
+            },
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                self.stream = None;
+                Err(Error::Timeout)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sets how long to wait for each reply, `None` waits forever. Once the
+    /// connection is dropped, it applies to the next one.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if let Some(ref stream) = self.stream {
+            stream.get_ref().set_read_timeout(timeout)?;
+        }
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd)?)
+    }
+
+    pub fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        let cmd = cmd.to_owned() + "\n";
+        self.send(&[cmd.as_bytes()])?;
+        let res = self.read_reply(0)?;
+        process_result(&cmd, res, 0)
+    }
+
+    /// Runs `cmd` and returns its output line by line, see `R2Pipe::cmd_lines`.
+    pub fn cmd_lines(&mut self, cmd: &str) -> Result<Lines<'_>> {
+        let cmd = cmd.to_owned() + "\n";
+        self.send(&[cmd.as_bytes()])?;
+        Ok(Lines::new(self.stream()?))
+    }
+
+    /// Runs `cmd` and reads its output up to `delim`, see `R2Pipe::cmd_until`.
+    pub fn cmd_until(&mut self, cmd: &str, delim: u8) -> Result<String> {
+        let cmd = cmd.to_owned() + "\n";
+        self.send(&[cmd.as_bytes()])?;
+        let res = self.read_reply(delim)?;
+        into_string(process_result(&cmd, res, delim)?)
+    }
+
+    pub fn cmd_with_binary(&mut self, cmd: &str, payload: &[u8]) -> Result<String> {
+        self.send(&[cmd.as_bytes(), b"\n", payload])?;
+        let res = self.read_reply(0)?;
+        into_string(process_result(cmd, res, 0)?)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
+        self.cmd_typed(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmd_typed<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<T> {
+        let res = self.cmd_bytes(cmd)?;
+        parse_json(cmd, &res)
+    }
+
+    pub fn close(&mut self) {
+        self.reopen = false;
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.get_ref().shutdown();
+        }
+    }
+}
+
+impl R2PipeTcp {
+    /// Returns the address the connection was made to, out of the ones the
+    /// address given to `R2Pipe::tcp` resolved to. It is kept after the
+    /// connection is closed.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr.1
+    }
+}
+
+#[cfg(feature = "ws")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "ws")))]
+impl R2PipeWs {
+    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
+        into_string(self.cmd_bytes(cmd)?)
+    }
+
+    pub fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        self.socket.send(Message::text(cmd))?;
+        loop {
+            match self.socket.read()? {
+                Message::Text(t) => return Ok(t.into_bytes()),
+                Message::Binary(b) => return Ok(b),
+                Message::Close(_) => return Err(Error::ConnectionClosed),
+                // control frames are answered by tungstenite itself
+                _ => {}
+            }
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmdj(&mut self, cmd: &str) -> Result<Value> {
+        self.cmd_typed(cmd)
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    pub fn cmd_typed<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<T> {
+        let res = self.cmd_bytes(cmd)?;
+        parse_json(cmd, &res)
+    }
+
+    pub fn close(&mut self) {
+        let _ = self.socket.close(None);
+    }
+}
+
+// Every pipe must stay movable to another thread, see the crate level
+// documentation.
+const _: () = {
+    fn assert_send<T: Send>() {}
+    #[cfg(feature = "http")]
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[allow(dead_code)]
+    fn assert_pipes_send() {
+        assert_send::<R2Pipe>();
+        assert_send::<R2PipeSpawn>();
+        assert_send::<R2PipeLang>();
+        assert_send::<R2PipeTcp>();
+        #[cfg(unix)]
+        assert_send::<R2PipeUnix>();
+        #[cfg(feature = "http")]
+        assert_send::<R2PipeHttp>();
+        #[cfg(feature = "http")]
+        assert_send_sync::<reqwest::Client>();
+        #[cfg(feature = "ws")]
+        assert_send::<R2PipeWs>();
+        assert_send::<R2PipeCustom>();
+        assert_send::<R2PipeThread>();
+        assert_send::<R2Threads>();
+    }
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn open_with_a_single_descriptor() {
+        use std::os::unix::io::AsRawFd;
+
+        let (mut r2, ours) = UnixStream::pair().unwrap();
+        let mut r2p = R2Pipe::open_fds(ours.as_raw_fd(), ours.as_raw_fd());
+        // the pipe holds its own duplicate of the descriptor
+        drop(ours);
+        match r2p {
+            R2Pipe::Lang(ref x) => assert!(x.write.is_none()),
+            _ => panic!("not a Lang pipe"),
+        }
+
+        let server = thread::spawn(move || {
+            let mut cmd = [0; 2];
+            r2.read_exact(&mut cmd).unwrap();
+            assert_eq!(&cmd, b"px");
+            r2.write_all(b"ok\0").unwrap();
+        });
+        assert_eq!(r2p.cmd("px").unwrap(), "ok");
+        server.join().unwrap();
+    }
+
+    /// Serves `replies` like `r2 -c '.:9080'`: one command per connection,
+    /// answered without a terminator.
+    fn stock_server(
+        replies: &'static [&'static str],
+    ) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut cmds = Vec::new();
+            for reply in replies {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut cmd = String::new();
+                stream.read_line(&mut cmd).unwrap();
+                cmds.push(cmd);
+                stream.get_mut().write_all(reply.as_bytes()).unwrap();
+            }
+            cmds
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn tcp_against_stock_server() {
+        let (addr, server) = stock_server(&["Hello World\n", "", "0x1000\n"]);
+        let mut r2p = R2Pipe::tcp(addr).unwrap();
+        assert_eq!(r2p.cmd("?e Hello World").unwrap(), "Hello World\n");
+        assert_eq!(r2p.cmd("s 0x1000").unwrap(), "");
+        assert_eq!(r2p.cmd("s").unwrap(), "0x1000\n");
+        assert_eq!(
+            server.join().unwrap(),
+            ["?e Hello World\n", "s 0x1000\n", "s\n"]
+        );
+        // nothing listens anymore
+        assert!(r2p.cmd("s").is_err());
+    }
+
+    #[test]
+    fn tcp_closed_after_terminated_reply() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut cmd = [0; 2];
+            stream.read_exact(&mut cmd).unwrap();
+            stream.write_all(b"ok\0").unwrap();
+        });
+        let mut r2p = R2Pipe::tcp(addr).unwrap();
+        assert_eq!(r2p.cmd("s").unwrap(), "ok");
+        server.join().unwrap();
+        match r2p.cmd("s") {
+            Err(Error::ConnectionClosed) => {}
+            res => panic!("{:?}", res),
+        }
+        match r2p.cmd("s") {
+            Err(Error::ConnectionClosed) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_against_stock_server() {
+        let path = env::temp_dir().join(format!("r2pipe-test-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            for reply in &["a\n", "b\n"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = BufReader::new(stream);
+                stream.read_line(&mut String::new()).unwrap();
+                stream.get_mut().write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        let mut r2p = R2Pipe::unix(&path).unwrap();
+        assert_eq!(r2p.cmd("?e a").unwrap(), "a\n");
+        assert_eq!(r2p.cmd("?e b").unwrap(), "b\n");
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn percent_encode_cmd() {
+        assert_eq!(percent_encode("pdf @ sym.main"), "pdf%20%40%20sym.main");
+        assert_eq!(percent_encode("o /bin/ls"), "o%20%2Fbin%2Fls");
+        assert_eq!(percent_encode("?e a~b"), "%3Fe%20a~b");
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn http_base_with_and_without_scheme() {
+        let base = |r2p: R2Pipe| match r2p {
+            R2Pipe::Http(x) => x.base,
+            _ => panic!("not an HTTP pipe"),
+        };
+        assert_eq!(
+            base(R2Pipe::http("localhost:9080")),
+            "http://localhost:9080"
+        );
+        assert_eq!(
+            base(R2Pipe::http("http://localhost:9080/")),
+            "http://localhost:9080"
+        );
+        let opts = R2PipeHttpOptions {
+            https: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            base(R2Pipe::http_with("r2.example.com", opts).unwrap()),
+            "https://r2.example.com"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn http_reuses_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut paths = Vec::new();
+            for _ in 0..2 {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let path = line.split_whitespace().nth(1).unwrap().to_owned();
+                // skip the headers
+                while line != "\r\n" {
+                    line.clear();
+                    stream.read_line(&mut line).unwrap();
+                }
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    path.len(),
+                    path
+                );
+                stream.get_mut().write_all(res.as_bytes()).unwrap();
+                paths.push(path);
+            }
+            // the second command went through the first connection
+            listener.set_nonblocking(true).unwrap();
+            assert!(listener.accept().is_err());
+            paths
+        });
+        let mut r2p = R2Pipe::http(&format!("http://{}/", addr));
+        assert_eq!(
+            r2p.cmd("pdf @ sym.main").unwrap(),
+            "/cmd/pdf%20%40%20sym.main"
+        );
+        assert_eq!(r2p.cmd("px 16").unwrap(), "/cmd/px%2016");
+        assert_eq!(
+            server.join().unwrap(),
+            ["/cmd/pdf%20%40%20sym.main", "/cmd/px%2016"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn drop_of_stuck_r2_is_bounded() {
+        // replies to the handshake, then neither reads nor quits
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("printf '\\000'; exec sleep 10")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.as_mut().unwrap();
+        set_nonblocking(stdin, true);
+        while stdin.write(&[b' '; 4096]).is_ok() {}
+        set_nonblocking(stdin, false);
+
+        let r2p = R2Pipe::from_child(child).unwrap();
+        let start = Instant::now();
+        drop(r2p);
+        assert!(start.elapsed() < EXIT_TIMEOUT * 4);
+    }
+
+    #[test]
+    fn spawn_with_an_empty_exepath_runs_r2() {
+        // spawning fails whether r2 is installed or not
+        let opts = R2PipeSpawnOptions {
+            cwd: Some(env::temp_dir().join("r2pipe-no-such-dir")),
+            ..Default::default()
+        };
+        match R2Pipe::spawn("-", Some(opts)) {
+            Err(Error::ExecutableNotFound(exe)) => assert_eq!(exe, "r2"),
+            res => panic!("{:?}", res.err()),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn thread_callback_runs_in_order_with_thread_id() {
+        let opts = R2PipeSpawnOptions::builder()
+            .exepath(crate::test_util::fake_r2().to_str().unwrap())
+            .build();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let cb: Arc<dyn Fn(u16, String) + Sync + Send> =
+            Arc::new(move |id, res| log.lock().unwrap().push((id, res)));
+        let mut threads = R2Threads::new(Some(cb));
+        let first = threads.add_pipe("a", Some(opts.clone()));
+        let second = threads.add_pipe("b", Some(opts));
+        assert_eq!((first, second), (0, 1));
+
+        let t = threads.get(second).unwrap();
+        let cmds: Vec<String> = (0..20).map(|i| format!("px {}", i)).collect();
+        for cmd in &cmds {
+            t.send(cmd.clone()).unwrap();
+        }
+        for (i, cmd) in cmds.iter().enumerate() {
+            let res = t.recv_response(true).unwrap();
+            assert_eq!((res.id, &res.cmd), (CommandId(i as u64), cmd));
+        }
+        for res in threads.join_all() {
+            res.unwrap();
+        }
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), cmds.len());
+        for ((id, res), cmd) in seen.iter().zip(&cmds) {
+            assert_eq!(*id, second);
+            assert!(res.contains(cmd.as_str()), "{} for {}", res, cmd);
+        }
+    }
+
+    /// Spawns `test_util::fake_r2`, logging the lines it reads to `log`.
+    #[cfg(unix)]
+    fn spawn_fake(log: &Path) -> R2Pipe {
+        let _ = std::fs::remove_file(log);
+        let opts = R2PipeSpawnOptions::builder()
+            .exepath(crate::test_util::fake_r2().to_str().unwrap())
+            .env("FAKE_R2_LOG", log.to_str().unwrap())
+            .build();
+        R2Pipe::spawn("-", Some(opts)).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cmd_batch_in_a_single_round_trip() {
+        let log = env::temp_dir().join(format!("r2pipe-batch-{}.log", process::id()));
+        let mut r2p = spawn_fake(&log);
+        let outs = r2p.cmd_batch(&["s 0x10", "px 4", "pd 1"]).unwrap();
+        assert_eq!(outs, ["\"s 0x10\"\n", "\"px 4\"\n", "\"pd 1\"\n"]);
+        let sent = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(sent.lines().count(), 1, "{}", sent);
+        r2p.close();
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cmd_batch_bounds_lines() {
+        let log = env::temp_dir().join(format!("r2pipe-batch-long-{}.log", process::id()));
+        let mut r2p = spawn_fake(&log);
+        let cmds: Vec<String> = (0..300).map(|i| format!("px 16 @ {:#x}", i * 16)).collect();
+        let long = format!("?e {}", "a".repeat(BATCH_LINE_MAX));
+        let mut all: Vec<&str> = cmds.iter().map(String::as_str).collect();
+        all.insert(100, &long);
+        let outs = r2p.cmd_batch(&all).unwrap();
+        assert_eq!(outs.len(), all.len());
+        for (out, cmd) in outs.iter().zip(&all) {
+            if cmd.starts_with("px") {
+                assert_eq!(*out, format!("\"{}\"\n", cmd));
+            }
+        }
+        assert_eq!(outs[100].trim_end(), &long[3..]);
+
+        let sent = std::fs::read_to_string(&log).unwrap();
+        assert!(sent.lines().count() > 2);
+        for line in sent.lines().filter(|l| l.len() > BATCH_LINE_MAX) {
+            assert_eq!(line, long);
+        }
+        r2p.close();
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[test]
+    fn commands_with_newline_are_rejected() {
+        let (mut r2p, sent) = crate::test_util::mock(&[("px", "ok")]);
+        for cmd in &["px\nx", "px\0x"] {
+            match r2p.cmd(cmd) {
+                Err(Error::InvalidCommand(ref c)) if c == cmd => {}
+                res => panic!("{:?}", res),
+            }
+        }
+        assert!(sent.lock().unwrap().is_empty());
+        // a trailing newline is trimmed off instead
+        assert_eq!(r2p.cmd("px\n").unwrap(), "ok");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_stays_in_sync_after_rejected_command() {
+        let log = env::temp_dir().join(format!("r2pipe-newline-{}.log", process::id()));
+        let mut r2p = spawn_fake(&log);
+        assert!(matches!(r2p.cmd("px\nx"), Err(Error::InvalidCommand(_))));
+        assert_eq!(r2p.cmd("px").unwrap(), "\"px\"\n");
+        assert_eq!(r2p.cmd("x").unwrap(), "\"x\"\n");
+        r2p.close();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "px\nx\nq!\n");
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[test]
+    fn evals_come_right_before_the_file() {
+        let opts = R2PipeSpawnOptions::builder()
+            .eval("bin.relocs.apply", "true")
+            .arg("-2")
+            .skip_plugins(true)
+            .eval("anal.depth", "64")
+            .build();
+        assert_eq!(
+            opts.r2_args(),
+            [
+                "-q0",
+                "-NN",
+                "-2",
+                "-e",
+                "bin.relocs.apply=true",
+                "-e",
+                "anal.depth=64"
+            ]
+        );
+        assert_eq!(R2PipeSpawnOptions::default().r2_args(), ["-q0"]);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn trim_json_tail_drops_stray_bytes() {
+        assert_eq!(trim_json_tail(r#"{"a":[1,2]}x"#), Some(r#"{"a":[1,2]}"#));
+        assert_eq!(
+            trim_json_tail(" [1,{\"b\":2}]\u{1}\n"),
+            Some(" [1,{\"b\":2}]")
+        );
+        // brackets in strings are not counted
+        assert_eq!(
+            trim_json_tail(r#"{"s":"}]\"{"}?"#),
+            Some(r#"{"s":"}]\"{"}"#)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn trim_json_tail_leaves_anything_else() {
+        // nothing to drop
+        assert_eq!(trim_json_tail(r#"{"a":1}"#), None);
+        assert_eq!(trim_json_tail("[1]\n"), None);
+        // not an object or an array
+        assert_eq!(trim_json_tail("12x"), None);
+        assert_eq!(trim_json_tail(""), None);
+        // unbalanced
+        assert_eq!(trim_json_tail(r#"{"a":[1}"#), None);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn cmdj_lenient_retries_without_tail() {
+        let (mut r2p, _) =
+            crate::test_util::mock(&[("ij", r#"{"bin":{"arch":"x86"}}\u{ff}"#), ("pj", "{")]);
+        assert!(matches!(r2p.cmdj("ij"), Err(Error::JsonParse { .. })));
+        assert_eq!(r2p.cmdj_lenient("ij").unwrap()["bin"]["arch"], "x86");
+        assert!(matches!(
+            r2p.cmdj_lenient("pj"),
+            Err(Error::JsonParse { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn handshake_skips_banner() {
+        let builder = R2PipeSpawnOptions::builder()
+            .exepath(crate::test_util::fake_r2().to_str().unwrap())
+            .env("FAKE_R2_BANNER", "radare2 wrapper 1.0\nloading...");
+        let mut r2p = R2Pipe::spawn("-", Some(builder.clone().build())).unwrap();
+        assert_eq!(r2p.cmd("px").unwrap(), "\"px\"\n");
+        assert_eq!(r2p.cmd("x").unwrap(), "\"x\"\n");
+        r2p.close();
+
+        // r2 started without -q0 prints no NUL, the banner is then read with
+        // the first reply
+        let opts = builder
+            .env("FAKE_R2_NO_HANDSHAKE", "1")
+            .skip_handshake(true)
+            .build();
+        let mut r2p = R2Pipe::spawn("-", Some(opts)).unwrap();
+        assert_eq!(
+            r2p.cmd("px").unwrap(),
+            "radare2 wrapper 1.0\nloading...\n\"px\"\n"
+        );
+        assert_eq!(r2p.cmd("x").unwrap(), "\"x\"\n");
+        r2p.close();
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn parse_json_from_bytes() {
+        let v: Vec<u64> = parse_json("pxqj", b"[1,2]\n").unwrap();
+        assert_eq!(v, [1, 2]);
+        assert!(matches!(
+            parse_json::<Value>("pxqj", b""),
+            Err(Error::EmptyResponse)
+        ));
+        match parse_json::<Value>("pxqj", b"[1,\xff]") {
+            Err(Error::JsonParse { cmd, raw, .. }) => {
+                assert_eq!((cmd.as_str(), raw.as_str()), ("pxqj", "[1,\u{fffd}]"));
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn cmd_typed_through_cache_and_strict_mode() {
+        let (mut r2p, sent) = crate::test_util::mock(&[("pxqj 16", "[16,32]")]);
+        let direct: Vec<u64> = r2p.cmd_typed("pxqj 16").unwrap();
+        r2p.enable_cache(8);
+        r2p.set_strict(true);
+        let cached: Vec<u64> = r2p.cmd_typed(" pxqj 16 ").unwrap();
+        let again: Vec<u64> = r2p.cmd_typed("pxqj 16").unwrap();
+        assert_eq!(direct, [16, 32]);
+        assert_eq!(cached, direct);
+        assert_eq!(again, direct);
+        // the last one came from the cache
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn reopen_two_files_in_sequence() {
+        let (mut r2p, sent) = crate::test_util::mock(&[
+            ("oj", r#"[{"fd":3,"uri":"/bin/ls"}]"#),
+            ("oj", r#"[{"fd":4,"uri":"/bin/cat"}]"#),
+        ]);
+        r2p.reopen("/bin/ls").unwrap();
+        r2p.reopen("/bin/cat").unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["o-*", "o \"/bin/ls\"", "oj", "o-*", "o \"/bin/cat\"", "oj"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn reopen_missing_file() {
+        let (mut r2p, _) =
+            crate::test_util::mock(&[("o \"/nope\"", "ERROR: Cannot open '/nope'"), ("oj", "[]")]);
+        match r2p.reopen("/nope") {
+            Err(Error::CommandFailed { cmd, output, .. }) => {
+                assert_eq!(cmd, "o \"/nope\"");
+                assert!(output.contains("Cannot open"));
+            }
+            res => panic!("{:?}", res),
+        }
+        assert!(matches!(r2p.reopen("a\"b"), Err(Error::InvalidCommand(_))));
+    }
+
+    /// Replies with nothing `empty` times, then with `ok`, or fails with
+    /// `Error::ConnectionClosed` if `broken`.
+    struct Flaky {
+        empty: u32,
+        broken: bool,
+        calls: Arc<Mutex<u32>>,
+    }
+
+    impl R2PipeTransport for Flaky {
+        fn cmd_bytes(&mut self, _: &str) -> Result<Vec<u8>> {
+            *self.calls.lock().unwrap() += 1;
+            if self.broken {
+                return Err(Error::ConnectionClosed);
+            }
+            if self.empty > 0 {
+                self.empty -= 1;
+                return Err(Error::EmptyResponse);
+            }
+            Ok(b"ok".to_vec())
+        }
+    }
+
+    fn flaky(empty: u32, broken: bool) -> (R2Pipe, Arc<Mutex<u32>>) {
+        let calls = Arc::new(Mutex::new(0));
+        let t = Flaky {
+            empty,
+            broken,
+            calls: Arc::clone(&calls),
+        };
+        (R2Pipe::from_transport(Box::new(t)), calls)
+    }
+
+    #[test]
+    fn cmd_retry_after_empty_replies() {
+        let (mut r2p, calls) = flaky(2, false);
+        assert_eq!(r2p.cmd_retry("pi 1", 3).unwrap(), "ok");
+        assert_eq!(*calls.lock().unwrap(), 3);
+
+        let (mut r2p, calls) = flaky(2, false);
+        assert!(matches!(
+            r2p.cmd_retry("pi 1", 2),
+            Err(Error::EmptyResponse)
+        ));
+        assert_eq!(*calls.lock().unwrap(), 2);
+        let (mut r2p, _) = flaky(1, false);
+        assert!(matches!(r2p.cmd("pi 1"), Err(Error::EmptyResponse)));
+    }
+
+    #[test]
+    fn cmd_retry_gives_up_on_other_errors() {
+        let (mut r2p, calls) = flaky(0, true);
+        assert!(matches!(
+            r2p.cmd_retry("pi 1", 5),
+            Err(Error::ConnectionClosed)
+        ));
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn metrics_count_the_commands_sent() {
+        let (mut r2p, calls) = flaky(0, false);
+        assert_eq!(r2p.last_cmd_duration(), None);
+        r2p.enable_metrics();
+        r2p.enable_cache(4);
+        assert_eq!(r2p.last_cmd_duration(), None);
+        r2p.cmd("pi 1").unwrap();
+        r2p.cmd("s 0x10").unwrap();
+        assert_eq!(r2p.cmd_count(), 2);
+        let last = r2p.last_cmd_duration().unwrap();
+        assert!(r2p.total_cmd_time() >= last);
+
+        // served from the cache
+        r2p.cmd("pi 2").unwrap();
+        r2p.cmd("pi 2").unwrap();
+        assert_eq!(*calls.lock().unwrap(), 3);
+        assert_eq!(r2p.cmd_count(), 3);
+
+        r2p.disable_metrics();
+        r2p.cmd("s 0x20").unwrap();
+        assert_eq!(r2p.cmd_count(), 0);
+        assert_eq!(r2p.total_cmd_time(), Duration::default());
+        assert_eq!(r2p.last_cmd_duration(), None);
+
+        let (mut r2p, _) = flaky(0, true);
+        r2p.enable_metrics();
+        assert!(r2p.cmd("pi 1").is_err());
+        assert_eq!(r2p.cmd_count(), 1);
+        assert!(r2p.last_cmd_duration().is_some());
+    }
+
+    #[test]
+    fn cmd_at_and_cmd_fmt() {
+        let (mut r2p, sent) = crate::test_util::mock(&[("pd 10 @ 0x401000", "ok")]);
+        assert_eq!(r2p.cmd_at(" pd 10 ", 0x401000).unwrap(), "ok");
+        r2p.cmd_fmt("afvn", &["new_name", "old_name"]).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["pd 10 @ 0x401000", "afvn new_name old_name"]
+        );
+    }
+
+    #[test]
+    fn multi_commands_are_rejected() {
+        let (mut r2p, sent) = crate::test_util::mock(&[]);
+        for cmd in &[
+            "pd 1; px",
+            "pd 1 | grep x",
+            "pd 1 @ 0x10",
+            "pd 1\npx",
+            "pd 1 ~call",
+        ] {
+            match r2p.cmd_at(cmd, 0x10) {
+                Err(Error::UnsafeCommand(ref c)) if c == cmd => {}
+                res => panic!("{:?}", res),
+            }
+        }
+        for arg in &["a;b", "a b", "", "`x`"] {
+            assert!(matches!(
+                r2p.cmd_fmt("afvn", &[*arg]),
+                Err(Error::UnsafeCommand(_))
+            ));
+        }
+        assert!(sent.lock().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_handshake_reaps_r2() {
+        // closes its stdout without the handshake, but keeps running
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg("exec >&-; sleep 10")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id() as libc::pid_t;
+        match R2Pipe::from_child(child) {
+            Err(Error::StartupFailed(ref why)) => assert_eq!(why, NO_HANDSHAKE),
+            res => panic!("{:?}", res.err()),
+        }
+        // neither running nor a zombie anymore
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_timeout_respawns_with_recovery() {
+        let log = env::temp_dir().join(format!("r2pipe-timeout-{}.log", process::id()));
+        let recovered = Arc::new(Mutex::new(0));
+        let count = Arc::clone(&recovered);
+        let mut r2p = spawn_fake(&log).with_recovery(RecoveryPolicy {
+            setup: vec!["e asm.bytes=false".to_owned()],
+            on_recover: Some(Arc::new(move |e| {
+                assert!(matches!(e, Error::CommandTimeout { .. }));
+                *count.lock().unwrap() += 1;
+            })),
+            ..Default::default()
+        });
+        let timeout = Some(Duration::from_millis(200));
+        r2p.set_timeout(timeout).unwrap();
+        let pid = match r2p {
+            R2Pipe::Pipe(ref x) => x.pid(),
+            _ => panic!("not a spawned pipe"),
+        };
+
+        match r2p.cmd("sleep 2") {
+            Err(Error::CommandTimeout { still_running }) => assert!(still_running),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(*recovered.lock().unwrap(), 1);
+        match r2p {
+            R2Pipe::Pipe(ref x) => assert_ne!(x.pid(), pid),
+            _ => panic!("not a spawned pipe"),
+        }
+        // the timeout set on the killed r2 applies to the new one
+        assert_eq!(r2p.timeout(), Some(timeout));
+        assert_eq!(r2p.cmd("?e hi").unwrap(), "hi\n");
+        let sent = std::fs::read_to_string(&log).unwrap();
+        assert!(sent.ends_with("e asm.bytes=false\n?e hi\n"), "{:?}", sent);
+        let _ = std::fs::remove_file(&log);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lang_timeout_skips_the_late_reply() {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+        let (mut r2, ours) = UnixStream::pair().unwrap();
+        let file = unsafe { File::from_raw_fd(ours.into_raw_fd()) };
+        let mut r2p = R2Pipe::Lang(R2PipeLang {
+            read: BufReader::new(LangIn::new(file)),
+            write: None,
+            stale: false,
+            state: PipeState::default(),
+        });
+        let timeout = Some(Duration::from_millis(100));
+        r2p.set_timeout(timeout).unwrap();
+        assert_eq!(r2p.timeout(), Some(timeout));
+
+        assert!(matches!(r2p.cmd("aaaa"), Err(Error::Timeout)));
+        // still busy with aaaa, the next command is not sent
+        assert!(matches!(r2p.cmd("px"), Err(Error::Timeout)));
+        r2.write_all(b"done\0").unwrap();
+        let server = thread::spawn(move || {
+            let mut cmd = [0; 6];
+            r2.read_exact(&mut cmd).unwrap();
+            assert_eq!(&cmd, b"aaaapx");
+            r2.write_all(b"ok\0").unwrap();
+        });
+        assert_eq!(r2p.cmd("px").unwrap(), "ok");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn end_reply_of_the_stock_server() {
+        let mut res = b"ok\0".to_vec();
+        assert_eq!(end_reply(&mut res, 0, false), ReplyEnd::Terminated);
+        assert_eq!(res, b"ok\0");
+        // no terminator, the server closed the connection after the output
+        let mut res = b"ok\n".to_vec();
+        assert_eq!(end_reply(&mut res, 0, false), ReplyEnd::Closed);
+        assert_eq!(res, b"ok\n\0");
+        let mut res = Vec::new();
+        assert_eq!(end_reply(&mut res, 0, false), ReplyEnd::Closed);
+        assert_eq!(res, b"\0");
+        // the connection carried a terminated reply before, r2 is gone
+        let mut res = Vec::new();
+        assert_eq!(end_reply(&mut res, 0, true), ReplyEnd::Lost);
+        let mut res = b"a,b".to_vec();
+        assert_eq!(end_reply(&mut res, b'\n', false), ReplyEnd::Closed);
+        assert_eq!(res, b"a,b");
+    }
 }
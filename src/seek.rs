@@ -0,0 +1,103 @@
+//! Helpers to move around the r2 session and navigate its seek history.
+
+use crate::{Error, R2Pipe, Result};
+
+#[cfg(feature = "json")]
+use serde_derive::Deserialize;
+
+/// Parses a number printed by r2, either in hex (`0x...`) or decimal.
+pub(crate) fn parse_addr(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let res = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        s.parse::<u64>()
+    };
+    res.map_err(|_| Error::UnexpectedResponse(s.to_owned()))
+}
+
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+struct HistoryEntry {
+    offset: u64,
+}
+
+impl R2Pipe {
+    /// Returns the current seek (`s`).
+    pub fn current_seek(&mut self) -> Result<u64> {
+        parse_addr(&self.cmd("s")?)
+    }
+
+    /// Seeks to `addr`.
+    pub fn seek(&mut self, addr: u64) -> Result<()> {
+        self.cmd(&format!("s {:#x}", addr))?;
+        Ok(())
+    }
+
+    /// Goes back to the previous seek (`s-`) and returns it.
+    ///
+    /// The seek is left unchanged when there is nothing to undo.
+    pub fn seek_undo(&mut self) -> Result<u64> {
+        self.cmd("s-")?;
+        self.current_seek()
+    }
+
+    /// Goes forward to the seek undone last (`s+`) and returns it.
+    ///
+    /// The seek is left unchanged when there is nothing to redo.
+    pub fn seek_redo(&mut self) -> Result<u64> {
+        self.cmd("s+")?;
+        self.current_seek()
+    }
+
+    #[cfg(feature = "json")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "json")))]
+    /// Returns the offsets in the seek history (`sj`), oldest first.
+    ///
+    /// The history is empty until the first seek.
+    pub fn seek_history(&mut self) -> Result<Vec<u64>> {
+        let json = self.cmdj("sj")?;
+        let entries: Vec<HistoryEntry> = serde_json::from_value(json)?;
+        Ok(entries.into_iter().map(|e| e.offset).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    #[test]
+    fn parse_hex_and_decimal() {
+        assert_eq!(parse_addr("0x401000\n").unwrap(), 0x40_1000);
+        assert_eq!(parse_addr("0xffffffffffffffff").unwrap(), u64::MAX);
+        assert_eq!(parse_addr(" 256 ").unwrap(), 256);
+        for garbage in ["", "0x", "0xzz", "-1", "entry0", "0x10 0x20"] {
+            assert!(
+                matches!(parse_addr(garbage), Err(Error::UnexpectedResponse(_))),
+                "{:?}",
+                garbage
+            );
+        }
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let (mut r2p, sent) = mock(&[("s", "0x1000\n"), ("s", "0x2000\n")]);
+        assert_eq!(r2p.seek_undo().unwrap(), 0x1000);
+        assert_eq!(r2p.seek_redo().unwrap(), 0x2000);
+        assert_eq!(*sent.lock().unwrap(), ["s-", "s", "s+", "s"]);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn history_from_sj() {
+        let (mut r2p, _) = mock(&[(
+            "sj",
+            r#"[{"offset":4096,"symbol":"entry0","current":false},
+            {"offset":8192,"symbol":"main","current":false},
+            {"offset":12288,"current":true}]"#,
+        )]);
+        assert_eq!(r2p.seek_history().unwrap(), [0x1000, 0x2000, 0x3000]);
+    }
+}
@@ -0,0 +1,118 @@
+//! Scoped changes of the seek and block size of an r2 session.
+
+use crate::config::Restore;
+use crate::seek::parse_addr;
+use crate::{R2Pipe, Result};
+
+use std::ops::{Deref, DerefMut};
+
+/// Wraps a `R2Pipe` to run operations at another seek or with another block
+/// size, restored once they are done.
+///
+/// It dereferences to the wrapped pipe, so every command stays available.
+///
+/// ```no_run
+/// # use r2pipe::{R2Pipe, Session};
+/// # fn main() -> r2pipe::Result<()> {
+/// let mut session = Session::new(R2Pipe::spawn("/bin/ls", None)?);
+/// let disasm = session.with_seek(0x1000, |r2p| r2p.cmd("pd 10"))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Session {
+    r2p: R2Pipe,
+}
+
+impl Session {
+    pub fn new(r2p: R2Pipe) -> Session {
+        Session { r2p }
+    }
+
+    /// Returns the wrapped pipe.
+    pub fn into_inner(self) -> R2Pipe {
+        self.r2p
+    }
+
+    /// Runs `f` with the session seeked to `addr`. The previous seek is
+    /// restored once `f` returns, even if it returned an error.
+    pub fn with_seek<T, F>(&mut self, addr: u64, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut R2Pipe) -> Result<T>,
+    {
+        let prev = self.r2p.current_seek()?;
+        self.r2p.seek(addr)?;
+        let guard = Restore {
+            r2p: &mut self.r2p,
+            cmd: format!("s {:#x}", prev),
+        };
+        f(guard.r2p)
+    }
+
+    /// Runs `f` with the block size set to `size` (`b size`). The previous
+    /// block size is restored once `f` returns, even if it returned an error.
+    pub fn with_block_size<T, F>(&mut self, size: u64, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut R2Pipe) -> Result<T>,
+    {
+        let prev = parse_addr(&self.r2p.cmd("b")?)?;
+        self.r2p.cmd(&format!("b {}", size))?;
+        let guard = Restore {
+            r2p: &mut self.r2p,
+            cmd: format!("b {}", prev),
+        };
+        f(guard.r2p)
+    }
+}
+
+impl Deref for Session {
+    type Target = R2Pipe;
+
+    fn deref(&self) -> &R2Pipe {
+        &self.r2p
+    }
+}
+
+impl DerefMut for Session {
+    fn deref_mut(&mut self) -> &mut R2Pipe {
+        &mut self.r2p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+    use crate::Error;
+
+    #[test]
+    fn with_seek_restores_the_seek() {
+        let (r2p, sent) = mock(&[("s", "0x100\n"), ("pd 1", "nop\n")]);
+        let mut session = Session::new(r2p);
+        let res = session.with_seek(0x2000, |r2p| r2p.cmd("pd 1"));
+        assert_eq!(res.unwrap(), "nop\n");
+        assert_eq!(*sent.lock().unwrap(), ["s", "s 0x2000", "pd 1", "s 0x100"]);
+    }
+
+    #[test]
+    fn with_seek_restores_the_seek_on_error() {
+        let (r2p, sent) = mock(&[("s", "0x100\n")]);
+        let mut session = Session::new(r2p);
+        let res: Result<()> = session.with_seek(0x2000, |_| Err(Error::NoDecompiler));
+        assert!(matches!(res, Err(Error::NoDecompiler)));
+        assert_eq!(*sent.lock().unwrap(), ["s", "s 0x2000", "s 0x100"]);
+    }
+
+    #[test]
+    fn with_block_size_restores_the_block_size() {
+        let (r2p, sent) = mock(&[("b", "0x100\n"), ("px", "0x1000 ...\n")]);
+        let mut session = Session::new(r2p);
+        let res = session.with_block_size(16, |r2p| r2p.cmd("px"));
+        assert_eq!(res.unwrap(), "0x1000 ...\n");
+        let res: Result<()> = session.with_block_size(32, |_| Err(Error::NoDecompiler));
+        assert!(res.is_err());
+        assert_eq!(
+            *sent.lock().unwrap(),
+            ["b", "b 16", "px", "b 256", "b", "b 32", "b 256"]
+        );
+    }
+}
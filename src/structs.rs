@@ -0,0 +1,372 @@
+//! Typed outputs of the commands listing what r2 found in the binary:
+//! functions, sections, symbols, imports and strings.
+
+use crate::{Error, R2Pipe, Result};
+
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+
+/// General information about the opened binary, as reported by `ij`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BinInfo {
+    /// Path of the opened file.
+    pub file: String,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Format name, eg. `elf64`, `pe` or `mach064`.
+    pub format: String,
+    pub arch: String,
+    pub bits: u32,
+    pub os: String,
+    pub endian: String,
+    pub machine: String,
+    /// Source language, guessed by r2.
+    pub lang: Option<String>,
+    pub stripped: bool,
+}
+
+#[derive(Deserialize, Default)]
+struct RawCore {
+    #[serde(default)]
+    file: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    format: String,
+}
+
+#[derive(Deserialize, Default)]
+struct RawBin {
+    #[serde(default)]
+    arch: String,
+    #[serde(default)]
+    bits: u32,
+    #[serde(default)]
+    os: String,
+    #[serde(default)]
+    endian: String,
+    #[serde(default)]
+    machine: String,
+    #[serde(default)]
+    lang: Option<String>,
+    #[serde(default)]
+    stripped: bool,
+}
+
+#[derive(Deserialize)]
+struct RawInfo {
+    #[serde(default)]
+    core: RawCore,
+    #[serde(default)]
+    bin: RawBin,
+}
+
+/// A function found by the analysis, as listed by `aflj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FunctionInfo {
+    pub name: String,
+    #[serde(rename = "offset")]
+    pub addr: u64,
+    /// Sum of the sizes of the basic blocks.
+    #[serde(default)]
+    pub size: u64,
+    #[serde(rename = "nbbs", default)]
+    pub blocks: u64,
+    /// `ninstr` in rizin.
+    #[serde(rename = "ninstrs", alias = "ninstr", default)]
+    pub instructions: u64,
+    /// Cyclomatic complexity.
+    #[serde(rename = "cc", default)]
+    pub complexity: u64,
+}
+
+/// A section of the binary, as listed by `iSj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SectionInfo {
+    pub name: String,
+    /// Size in the file.
+    #[serde(default)]
+    pub size: u64,
+    /// Size once mapped in memory.
+    #[serde(default)]
+    pub vsize: u64,
+    /// Permissions, eg. `-r-x`.
+    #[serde(default)]
+    pub perm: String,
+    #[serde(default)]
+    pub paddr: u64,
+    #[serde(default)]
+    pub vaddr: u64,
+}
+
+/// A symbol of the binary, as listed by `isj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SymbolInfo {
+    pub name: String,
+    /// The name before r2 prefixed or demangled it.
+    #[serde(default)]
+    pub realname: Option<String>,
+    /// Eg. `FUNC`, `OBJECT` or `NOTYPE`.
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    /// Eg. `GLOBAL`, `LOCAL` or `WEAK`.
+    #[serde(default)]
+    pub bind: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub paddr: u64,
+    #[serde(default)]
+    pub vaddr: u64,
+}
+
+/// A symbol imported by the binary, as listed by `iij`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportInfo {
+    pub name: String,
+    #[serde(default)]
+    pub ordinal: u64,
+    /// Eg. `FUNC` or `OBJECT`.
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    #[serde(default)]
+    pub bind: String,
+    /// Address of the PLT entry, when there is one.
+    #[serde(default)]
+    pub plt: Option<u64>,
+}
+
+/// A string found in the data sections, as listed by `izj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StringInfo {
+    pub string: String,
+    #[serde(default)]
+    pub vaddr: u64,
+    #[serde(default)]
+    pub paddr: u64,
+    /// Size in bytes, terminator included.
+    #[serde(default)]
+    pub size: u64,
+    /// Number of characters.
+    #[serde(default)]
+    pub length: u64,
+    #[serde(default)]
+    pub section: String,
+    /// Encoding, eg. `ascii`, `utf8` or `utf16le`.
+    #[serde(rename = "type", default)]
+    pub encoding: String,
+}
+
+impl R2Pipe {
+    /// Returns general information about the opened binary (`ij`).
+    pub fn bin_info(&mut self) -> Result<BinInfo> {
+        let raw: RawInfo = self.cmd_typed("ij")?;
+        Ok(BinInfo {
+            file: raw.core.file,
+            size: raw.core.size,
+            format: raw.core.format,
+            arch: raw.bin.arch,
+            bits: raw.bin.bits,
+            os: raw.bin.os,
+            endian: raw.bin.endian,
+            machine: raw.bin.machine,
+            lang: raw.bin.lang,
+            stripped: raw.bin.stripped,
+        })
+    }
+
+    /// Returns the functions found so far (`aflj`), none before the binary
+    /// is analyzed, eg. with `aa`.
+    pub fn functions(&mut self) -> Result<Vec<FunctionInfo>> {
+        self.list("aflj")
+    }
+
+    /// Returns the sections of the binary (`iSj`).
+    pub fn sections(&mut self) -> Result<Vec<SectionInfo>> {
+        self.list("iSj")
+    }
+
+    /// Returns the symbols of the binary (`isj`).
+    pub fn symbols(&mut self) -> Result<Vec<SymbolInfo>> {
+        self.list("isj")
+    }
+
+    /// Returns the symbols imported by the binary (`iij`).
+    pub fn imports(&mut self) -> Result<Vec<ImportInfo>> {
+        self.list("iij")
+    }
+
+    /// Returns the strings found in the data sections (`izj`).
+    pub fn strings(&mut self) -> Result<Vec<StringInfo>> {
+        self.list("izj")
+    }
+
+    /// Runs `cmd`, which lists items in a JSON array. Some r2 builds print
+    /// nothing at all rather than `[]` when there are none.
+    fn list<T: DeserializeOwned>(&mut self, cmd: &str) -> Result<Vec<T>> {
+        match self.cmd_typed(cmd) {
+            Err(Error::EmptyResponse) => Ok(vec![]),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    #[test]
+    fn bin_info_from_nested_ij() {
+        let (mut r2p, _) = mock(&[(
+            "ij",
+            r#"{"core":{"type":"DYN (Shared object file)","file":"/bin/ls","fd":3,
+            "size":142312,"humansz":"139.0K","iorw":false,"mode":"r-x","block":256,
+            "format":"elf64"},"bin":{"arch":"x86","baddr":0,"binsz":140820,"bintype":"elf",
+            "bits":64,"canary":true,"class":"ELF64","compiler":"GCC: (GNU) 12.2.0",
+            "endian":"little","lang":"c","machine":"AMD x86-64 architecture","nx":true,
+            "os":"linux","pic":true,"static":false,"stripped":true}}"#,
+        )]);
+        assert_eq!(
+            r2p.bin_info().unwrap(),
+            BinInfo {
+                file: "/bin/ls".to_owned(),
+                size: 142312,
+                format: "elf64".to_owned(),
+                arch: "x86".to_owned(),
+                bits: 64,
+                os: "linux".to_owned(),
+                endian: "little".to_owned(),
+                machine: "AMD x86-64 architecture".to_owned(),
+                lang: Some("c".to_owned()),
+                stripped: true,
+            }
+        );
+    }
+
+    #[test]
+    fn bin_info_without_bin() {
+        let (mut r2p, _) = mock(&[("ij", r#"{"core":{"file":"malloc://512","size":512}}"#)]);
+        let info = r2p.bin_info().unwrap();
+        assert_eq!(info.file, "malloc://512");
+        assert_eq!(info.size, 512);
+        assert_eq!(info.lang, None);
+        assert_eq!(info.arch, "");
+    }
+
+    #[test]
+    fn functions_from_r2_and_rizin() {
+        let (mut r2p, _) = mock(&[(
+            "aflj",
+            r#"[{"offset":16400,"name":"main","size":1403,"is-pure":"false","realsz":1403,
+            "noreturn":false,"stackframe":104,"calltype":"amd64","cost":621,"cc":26,
+            "bits":64,"type":"fcn","nbbs":54,"is-lineal":false,"ninstrs":394,"edges":78},
+            {"offset":4096,"name":"entry0","size":38,"nbbs":1,"ninstr":12,"cc":1}]"#,
+        )]);
+        assert_eq!(
+            r2p.functions().unwrap(),
+            [
+                FunctionInfo {
+                    name: "main".to_owned(),
+                    addr: 0x4010,
+                    size: 1403,
+                    blocks: 54,
+                    instructions: 394,
+                    complexity: 26,
+                },
+                FunctionInfo {
+                    name: "entry0".to_owned(),
+                    addr: 0x1000,
+                    size: 38,
+                    blocks: 1,
+                    instructions: 12,
+                    complexity: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn sections_symbols_imports_and_strings() {
+        let (mut r2p, _) = mock(&[
+            (
+                "iSj",
+                r#"[{"name":".text","size":77394,"vsize":77394,"perm":"-r-x","paddr":18320,
+                "vaddr":18320}]"#,
+            ),
+            (
+                "isj",
+                r#"[{"name":"imp.free","flagname":"sym.imp.free","realname":"free",
+                "ordinal":1,"bind":"GLOBAL","size":16,"type":"FUNC","vaddr":17872,
+                "paddr":17872,"is_imported":true}]"#,
+            ),
+            (
+                "iij",
+                r#"[{"ordinal":1,"bind":"GLOBAL","type":"FUNC","name":"free","plt":17872},
+                {"ordinal":2,"bind":"WEAK","type":"NOTYPE","name":"__gmon_start__"}]"#,
+            ),
+            (
+                "izj",
+                r#"[{"vaddr":103016,"paddr":103016,"ordinal":0,"size":6,"length":5,
+                "section":".rodata","type":"ascii","string":"hello"}]"#,
+            ),
+        ]);
+        assert_eq!(
+            r2p.sections().unwrap(),
+            [SectionInfo {
+                name: ".text".to_owned(),
+                size: 77394,
+                vsize: 77394,
+                perm: "-r-x".to_owned(),
+                paddr: 18320,
+                vaddr: 18320,
+            }]
+        );
+        assert_eq!(
+            r2p.symbols().unwrap(),
+            [SymbolInfo {
+                name: "imp.free".to_owned(),
+                realname: Some("free".to_owned()),
+                kind: "FUNC".to_owned(),
+                bind: "GLOBAL".to_owned(),
+                size: 16,
+                paddr: 17872,
+                vaddr: 17872,
+            }]
+        );
+        let imports = r2p.imports().unwrap();
+        assert_eq!(imports.len(), 2);
+        assert_eq!(imports[0].plt, Some(17872));
+        assert_eq!(
+            imports[1],
+            ImportInfo {
+                name: "__gmon_start__".to_owned(),
+                ordinal: 2,
+                kind: "NOTYPE".to_owned(),
+                bind: "WEAK".to_owned(),
+                plt: None,
+            }
+        );
+        assert_eq!(
+            r2p.strings().unwrap(),
+            [StringInfo {
+                string: "hello".to_owned(),
+                vaddr: 103016,
+                paddr: 103016,
+                size: 6,
+                length: 5,
+                section: ".rodata".to_owned(),
+                encoding: "ascii".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn empty_lists() {
+        let (mut r2p, _) = mock(&[("iSj", "[]")]);
+        assert!(r2p.sections().unwrap().is_empty());
+        // nothing printed at all rather than []
+        assert!(r2p.functions().unwrap().is_empty());
+        assert!(r2p.strings().unwrap().is_empty());
+    }
+}
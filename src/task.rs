@@ -0,0 +1,179 @@
+//! Helpers to run commands as r2 background tasks, the `&` command family.
+
+use crate::{Error, R2Pipe, Result};
+
+use serde_derive::Deserialize;
+
+/// State of a background task, as listed by `&j`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// Created, not scheduled yet.
+    BeforeStart,
+    /// Queued, waiting for its turn.
+    Sleeping,
+    Running,
+    Done,
+    /// A state this version does not know of.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize)]
+struct RawTask {
+    id: u32,
+    #[serde(rename = "state")]
+    status: TaskStatus,
+    #[serde(default)]
+    cmd: String,
+}
+
+/// A command running as an r2 background task, see `R2Pipe::cmd_task`.
+///
+/// The handle does not hold the pipe, each of its methods takes the one the
+/// task was started on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskHandle {
+    pub id: u32,
+}
+
+impl TaskHandle {
+    /// Returns the state of the task, `None` once it was deleted.
+    pub fn status(&self, r2p: &mut R2Pipe) -> Result<Option<TaskStatus>> {
+        let tasks: Vec<RawTask> = r2p.cmd_typed("&j")?;
+        Ok(tasks
+            .into_iter()
+            .find(|t| t.id == self.id)
+            .map(|t| t.status))
+    }
+
+    /// Returns the output of the task if it is done, without blocking.
+    pub fn poll(&self, r2p: &mut R2Pipe) -> Result<Option<String>> {
+        match self.status(r2p)? {
+            Some(TaskStatus::Done) => Ok(Some(self.output(r2p)?)),
+            Some(_) => Ok(None),
+            None => Err(Error::UnexpectedResponse(format!("no task {}", self.id))),
+        }
+    }
+
+    /// Blocks until the task is done (`&&`) and returns its output. The pipe
+    /// can not be used meanwhile.
+    pub fn wait(&self, r2p: &mut R2Pipe) -> Result<String> {
+        r2p.cmd(&format!("&& {}", self.id))?;
+        self.output(r2p)
+    }
+
+    /// Interrupts the task (`&b`) and deletes it (`&-`).
+    pub fn cancel(&self, r2p: &mut R2Pipe) -> Result<()> {
+        r2p.cmd(&format!("&b {}", self.id))?;
+        r2p.cmd(&format!("&- {}", self.id))?;
+        Ok(())
+    }
+
+    fn output(&self, r2p: &mut R2Pipe) -> Result<String> {
+        r2p.cmd(&format!("&= {}", self.id))
+    }
+}
+
+impl R2Pipe {
+    /// Starts `cmd` as an r2 background task (`& cmd`), so other commands
+    /// can be run while it goes on, eg. `aaa` on a big binary.
+    ///
+    /// The task's output is kept by r2 until it is fetched through the
+    /// returned handle. Commands touching the state the task works on, such
+    /// as the analysis, see it half done.
+    ///
+    /// r2 does not print the id of the task, which is found in `&j` as the
+    /// only task running `cmd` not listed before starting it.
+    /// `Error::UnexpectedResponse` is returned if there is none, or several,
+    /// eg. when another client started the same command meanwhile.
+    pub fn cmd_task(&mut self, cmd: &str) -> Result<TaskHandle> {
+        let cmd = cmd.trim();
+        let before: Vec<RawTask> = self.cmd_typed("&j")?;
+        self.cmd(&format!("& {}", cmd))?;
+        let after: Vec<RawTask> = self.cmd_typed("&j")?;
+        let mut started = after
+            .into_iter()
+            .filter(|t| t.cmd == cmd && before.iter().all(|b| b.id != t.id));
+        match (started.next(), started.next()) {
+            (Some(t), None) => Ok(TaskHandle { id: t.id }),
+            (None, _) => Err(Error::UnexpectedResponse(format!("no task for {}", cmd))),
+            (Some(_), Some(_)) => Err(Error::UnexpectedResponse(format!(
+                "several tasks for {}",
+                cmd
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+
+    const TASKS: &str = r#"[{"id":0,"state":"running","transient":false,"cmd":"main"},
+        {"id":1,"state":"done","transient":false,"cmd":"aaa"},
+        {"id":2,"state":"before_start","transient":false,"cmd":"aac"},
+        {"id":3,"state":"zombie","transient":true,"cmd":"pd"}]"#;
+
+    #[test]
+    fn cmd_task_finds_the_new_task() {
+        let (mut r2p, sent) = mock(&[
+            ("&j", r#"[{"id":0,"state":"running","cmd":"main"}]"#),
+            // another task, started by someone else meanwhile, comes last
+            (
+                "&j",
+                r#"[{"id":0,"state":"running","cmd":"main"},
+                {"id":1,"state":"sleeping","cmd":"aaa"},
+                {"id":2,"state":"before_start","cmd":"pd 10"}]"#,
+            ),
+        ]);
+        assert_eq!(r2p.cmd_task(" aaa ").unwrap(), TaskHandle { id: 1 });
+        assert_eq!(*sent.lock().unwrap(), ["&j", "& aaa", "&j"]);
+    }
+
+    #[test]
+    fn cmd_task_without_a_single_match() {
+        let (mut r2p, _) = mock(&[
+            ("&j", r#"[{"id":1,"state":"running","cmd":"aaa"}]"#),
+            (
+                "&j",
+                r#"[{"id":1,"state":"running","cmd":"aaa"},
+                {"id":2,"state":"running","cmd":"aaa"},
+                {"id":3,"state":"sleeping","cmd":"aaa"}]"#,
+            ),
+        ]);
+        match r2p.cmd_task("aaa") {
+            Err(Error::UnexpectedResponse(ref why)) => assert_eq!(why, "several tasks for aaa"),
+            res => panic!("unexpected {:?}", res),
+        }
+        // the last list sticks, nothing new is found
+        match r2p.cmd_task("aaa") {
+            Err(Error::UnexpectedResponse(ref why)) => assert_eq!(why, "no task for aaa"),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn status_reads_the_state_of_each_task() {
+        let (mut r2p, _) = mock(&[("&j", TASKS)]);
+        let status = |id, r2p: &mut R2Pipe| TaskHandle { id }.status(r2p).unwrap();
+        assert_eq!(status(0, &mut r2p), Some(TaskStatus::Running));
+        assert_eq!(status(1, &mut r2p), Some(TaskStatus::Done));
+        assert_eq!(status(2, &mut r2p), Some(TaskStatus::BeforeStart));
+        assert_eq!(status(3, &mut r2p), Some(TaskStatus::Unknown));
+        assert_eq!(status(4, &mut r2p), None);
+    }
+
+    #[test]
+    fn poll_fetches_the_output_once_done() {
+        let (mut r2p, sent) = mock(&[("&j", TASKS), ("&= 1", "entry0\n")]);
+        assert_eq!(TaskHandle { id: 2 }.poll(&mut r2p).unwrap(), None);
+        assert_eq!(
+            TaskHandle { id: 1 }.poll(&mut r2p).unwrap().as_deref(),
+            Some("entry0\n")
+        );
+        assert!(TaskHandle { id: 9 }.poll(&mut r2p).is_err());
+        assert_eq!(*sent.lock().unwrap(), ["&j", "&j", "&= 1", "&j"]);
+    }
+}
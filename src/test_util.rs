@@ -0,0 +1,90 @@
+//! A transport replying from a table of commands, and a fake r2 to spawn,
+//! for the unit tests.
+
+use crate::{R2Pipe, R2PipeTransport, Result};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::Once;
+use std::sync::{Arc, Mutex};
+
+/// Commands received by a `Mock`, in order.
+pub(crate) type Sent = Arc<Mutex<Vec<String>>>;
+
+/// Replies to each command with the output paired with it. A command listed
+/// several times gets the outputs one after the other, the last one sticking.
+/// Unknown commands print nothing.
+pub(crate) struct Mock {
+    replies: Vec<(String, String)>,
+    sent: Sent,
+}
+
+impl R2PipeTransport for Mock {
+    fn cmd_bytes(&mut self, cmd: &str) -> Result<Vec<u8>> {
+        self.sent.lock().unwrap().push(cmd.to_owned());
+        let found: Vec<usize> = (0..self.replies.len())
+            .filter(|&i| self.replies[i].0 == cmd)
+            .collect();
+        let out = match *found.as_slice() {
+            [] => String::new(),
+            [i] => self.replies[i].1.clone(),
+            [i, ..] => self.replies.remove(i).1,
+        };
+        Ok(out.into_bytes())
+    }
+}
+
+/// Returns a pipe backed by a `Mock` and the list of commands it receives.
+pub(crate) fn mock(replies: &[(&str, &str)]) -> (R2Pipe, Sent) {
+    let sent = Sent::default();
+    let mock = Mock {
+        replies: replies
+            .iter()
+            .map(|&(c, r)| (c.to_owned(), r.to_owned()))
+            .collect(),
+        sent: sent.clone(),
+    };
+    (R2Pipe::from_transport(Box::new(mock)), sent)
+}
+
+/// Speaks the r2pipe protocol, replying to each command with the command
+/// itself as a JSON string, or its argument for `?e`, and exits on `q!`.
+/// `sleep N` waits `N` seconds before replying.
+/// `;`-separated commands are run one after the other, every line read is
+/// appended to the file named by `FAKE_R2_LOG`, if set. `FAKE_R2_BANNER` is
+/// printed before the handshake, like wrapper scripts do, which is left out
+/// if `FAKE_R2_NO_HANDSHAKE` is set.
+#[cfg(unix)]
+const FAKE_R2: &str = r#"#!/bin/sh
+[ -n "$FAKE_R2_BANNER" ] && printf '%s\n' "$FAKE_R2_BANNER"
+[ -z "$FAKE_R2_NO_HANDSHAKE" ] && printf '\000'
+while IFS= read -r line; do
+    [ -n "$FAKE_R2_LOG" ] && printf '%s\n' "$line" >>"$FAKE_R2_LOG"
+    [ "$line" = "q!" ] && exit 0
+    IFS=';'
+    for cmd in $line; do
+        case "$cmd" in
+        "?e "*) printf '%s\n' "${cmd#"?e "}" ;;
+        "sleep "*) sleep "${cmd#"sleep "}" && printf '"%s"\n' "$cmd" ;;
+        *) printf '"%s"\n' "$cmd" ;;
+        esac
+    done
+    unset IFS
+    printf '\000'
+done
+"#;
+
+/// Returns the path of a `FAKE_R2` script, to spawn in place of r2.
+#[cfg(unix)]
+pub(crate) fn fake_r2() -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    static WRITE: Once = Once::new();
+    let path = std::env::temp_dir().join(format!("r2pipe-fake-r2-{}", std::process::id()));
+    WRITE.call_once(|| {
+        std::fs::write(&path, FAKE_R2).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    });
+    path
+}
@@ -0,0 +1,94 @@
+//! Helpers to find out which r2 is on the other end of the pipe.
+
+use crate::{R2Pipe, Result};
+
+use serde_derive::Deserialize;
+
+/// The version of r2, as reported by `?Vj`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct R2Version {
+    #[serde(default)]
+    pub major: u32,
+    #[serde(default)]
+    pub minor: u32,
+    #[serde(default)]
+    pub patch: u32,
+    /// Number of commits since the release, 0 for release builds.
+    #[serde(rename = "commit", default)]
+    pub build: u32,
+    /// The version string, eg. `5.9.0` or `5.9.1-git`.
+    #[serde(rename = "version", default)]
+    pub raw: String,
+}
+
+impl R2Version {
+    /// Returns whether this is at least `major.minor.patch`.
+    pub fn at_least(&self, major: u32, minor: u32, patch: u32) -> bool {
+        (self.major, self.minor, self.patch) >= (major, minor, patch)
+    }
+}
+
+impl R2Pipe {
+    /// Returns the version of r2 (`?Vj`).
+    ///
+    /// When the numbers are missing, as with rizin, they are parsed from the
+    /// version string.
+    pub fn version(&mut self) -> Result<R2Version> {
+        let mut res: R2Version = self.cmd_typed("?Vj")?;
+        if (res.major, res.minor, res.patch) == (0, 0, 0) {
+            let mut nums = res
+                .raw
+                .split(|c: char| !c.is_ascii_digit())
+                .filter(|n| !n.is_empty())
+                .map(|n| n.parse().unwrap_or(0));
+            res.major = nums.next().unwrap_or(0);
+            res.minor = nums.next().unwrap_or(0);
+            res.patch = nums.next().unwrap_or(0);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock;
+    use crate::Error;
+
+    #[test]
+    fn version_of_r2() {
+        let vj = r#"{"arch":"x86","os":"linux","bits":64,"commit":29,"tap":"5.9.0-29-gc0ffee",
+            "nversion":50900,"version":"5.9.0","major":5,"minor":9,"patch":0}"#;
+        let (mut r2p, _) = mock(&[("?Vj", vj)]);
+        let v = r2p.version().unwrap();
+        assert_eq!(
+            v,
+            R2Version {
+                major: 5,
+                minor: 9,
+                patch: 0,
+                build: 29,
+                raw: "5.9.0".to_owned(),
+            }
+        );
+        assert!(v.at_least(5, 8, 9));
+        assert!(v.at_least(5, 9, 0));
+        assert!(!v.at_least(5, 10, 0));
+    }
+
+    #[test]
+    fn version_parsed_from_string() {
+        let (mut r2p, _) = mock(&[("?Vj", r#"{"version":"0.7.3-git"}"#)]);
+        let v = r2p.version().unwrap();
+        assert_eq!((v.major, v.minor, v.patch, v.build), (0, 7, 3, 0));
+    }
+
+    #[test]
+    fn version_parse_failure() {
+        let (mut r2p, _) = mock(&[("?Vj", "5.9.0")]);
+        match r2p.version() {
+            Err(Error::JsonParse { ref cmd, .. }) => assert_eq!(cmd, "?Vj"),
+            res => panic!("{:?}", res),
+        }
+    }
+}